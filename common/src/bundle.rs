@@ -1,47 +1,419 @@
+use crate::envelope::Error;
+use crate::hybrid_kem::HybridCapsule;
+use crate::symmetric_cipher::ContentCipher;
+use anyhow::Context;
+use bincode::Options;
 use log::info;
+use openssl::rand::rand_bytes;
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
-use std::ffi::OsStr;
 use std::fs::File;
 use std::io::ErrorKind::NotFound;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prefix of versioned bundles. Unversioned (version 1) bundles start directly
+/// with the ciphertext length, which can never match this.
+const MAGIC: &[u8; 4] = b"FEB\0";
+pub const FORMAT_VERSION: u8 = 5;
+
+/// Upper bound for a serialized bundle, far above any form submission
+pub const MAX_BUNDLE_SIZE: usize = 64 * 1024 * 1024;
+const MAX_SHARES: usize = u8::MAX as usize;
+
+/// Bundles come from untrusted queues, so length prefixes are bounded and
+/// nothing may follow the encoded bundle
+fn deserialize<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_BUNDLE_SIZE as u64)
+        .reject_trailing_bytes()
+        .deserialize(data)?)
+}
+
+/// How `Bundle::enc_key` was wrapped for the recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeyWrap {
+    /// RSA PKCS#1 v1.5 encryption of the content key
+    RsaPkcs1,
+    /// AES key wrap under a key derived from X25519 + ML-KEM-768
+    HybridKem(HybridCapsule),
+    /// Content key split into shares, each wrapped to a different key.
+    /// `Bundle::enc_key` is empty.
+    Threshold(ThresholdWrap),
+}
+
+impl KeyWrap {
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            KeyWrap::RsaPkcs1 => "RSA1_5",
+            KeyWrap::HybridKem(_) => "X25519-ML-KEM-768+A256KW",
+            KeyWrap::Threshold(_) => "Shamir-GF256",
+        }
+    }
+}
+
+/// How a share of a threshold bundle was wrapped, `KeyWrap` without the
+/// threshold so that shares can not nest. Encoded the same as those variants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ShareWrap {
+    RsaPkcs1,
+    HybridKem(HybridCapsule),
+}
+
+impl ShareWrap {
+    pub fn algorithm(&self) -> &'static str {
+        KeyWrap::from(self.clone()).algorithm()
+    }
+}
+
+impl From<ShareWrap> for KeyWrap {
+    fn from(share_wrap: ShareWrap) -> Self {
+        match share_wrap {
+            ShareWrap::RsaPkcs1 => KeyWrap::RsaPkcs1,
+            ShareWrap::HybridKem(capsule) => KeyWrap::HybridKem(capsule),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThresholdWrap {
+    pub threshold: u8,
+    pub shares: Vec<WrappedShare>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedShare {
+    pub index: u8,
+    pub key_id: String,
+    pub key_wrap: ShareWrap,
+    pub enc_share: Vec<u8>,
+}
+
+/// Identifies a single submission. Authenticated together with the content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Random, unique per submission
+    pub submission_id: [u8; 16],
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Missing from format version 4, and from bundles of senders without a counter
+    pub sequence: Option<Sequence>,
+}
+
+/// Position of a submission in the stream of one encrypting instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequence {
+    pub sender: String,
+    pub number: u64,
+}
+
+impl Metadata {
+    /// Fresh metadata for a submission made now
+    pub fn generate(sequence: Option<Sequence>) -> Result<Self, anyhow::Error> {
+        let mut submission_id = [0u8; 16];
+        rand_bytes(&mut submission_id)?;
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(Self {
+            submission_id,
+            created,
+            sequence,
+        })
+    }
+
+    pub fn submission_id_hex(&self) -> String {
+        hex::encode(self.submission_id)
+    }
+
+    /// Time since creation, zero if the creation time is in the future
+    pub fn age(&self) -> Duration {
+        let created = UNIX_EPOCH + Duration::from_secs(self.created);
+        SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default()
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Bundle {
     pub ciphertext: Vec<u8>,
     pub enc_key: Vec<u8>,
+    pub key_wrap: KeyWrap,
+    pub key_id: Option<String>,
+    pub content_cipher: ContentCipher,
+    /// Missing from format versions 1 to 3
+    pub metadata: Option<Metadata>,
+}
+
+#[derive(Deserialize)]
+struct LegacyBundle {
+    ciphertext: Vec<u8>,
+    enc_key: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct BundleV2 {
+    ciphertext: Vec<u8>,
+    enc_key: Vec<u8>,
+    key_wrap: KeyWrap,
+    key_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MetadataV4 {
+    submission_id: [u8; 16],
+    created: u64,
+}
+
+#[derive(Deserialize)]
+struct BundleV4 {
+    ciphertext: Vec<u8>,
+    enc_key: Vec<u8>,
+    key_wrap: KeyWrap,
+    key_id: Option<String>,
+    content_cipher: ContentCipher,
+    metadata: Option<MetadataV4>,
+}
+
+#[derive(Deserialize)]
+struct BundleV3 {
+    ciphertext: Vec<u8>,
+    enc_key: Vec<u8>,
+    key_wrap: KeyWrap,
+    key_id: Option<String>,
+    content_cipher: ContentCipher,
 }
 
 impl Bundle {
+    /// Format version of serialized bundle data, without parsing the rest of it
+    pub fn format_version(data: &[u8]) -> Option<u8> {
+        match data.strip_prefix(MAGIC) {
+            None => Some(1),
+            Some(rest) => rest.first().copied(),
+        }
+    }
+
+    pub fn content_algorithm(&self) -> &'static str {
+        self.content_cipher.name()
+    }
+
+    /// Header fields covered by the content cipher's authentication tag. The key
+    /// wrapping is left out so that bundles can be re-wrapped to another key.
+    pub fn authenticated_data(&self) -> Vec<u8> {
+        match &self.metadata {
+            None => bincode::serialize(&self.content_cipher),
+            // Same encoding as the sequence-less metadata of format version 4
+            Some(Metadata {
+                submission_id,
+                created,
+                sequence: None,
+            }) => bincode::serialize(&(self.content_cipher, submission_id, created)),
+            Some(metadata) => bincode::serialize(&(self.content_cipher, metadata)),
+        }
+        .expect("Serializing plain data cannot fail")
+    }
+
+    /// Whether the bundle carries submission metadata, from format version 4 on
+    pub fn has_metadata(&self) -> bool {
+        self.metadata.is_some()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.len() > MAX_BUNDLE_SIZE {
+            return Err(Error::Malformed(format!(
+                "{} bytes exceeds the maximum of {}",
+                data.len(),
+                MAX_BUNDLE_SIZE
+            )));
+        }
+
+        let bundle = Self::parse(data)?;
+        bundle.validate()?;
+        Ok(bundle)
+    }
+
+    /// Reads a bundle file, refusing to read past `MAX_BUNDLE_SIZE`
+    pub fn read_from_path(path: &Path) -> Result<Self, anyhow::Error> {
+        let mut data = Vec::new();
+        File::open(path)
+            .context(format!("Error opening bundle: {:?}", path))?
+            .take(MAX_BUNDLE_SIZE as u64 + 1)
+            .read_to_end(&mut data)
+            .context(format!("Error reading bundle: {:?}", path))?;
+        Ok(Self::from_bytes(&data)?)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        match data.strip_prefix(MAGIC) {
+            None => {
+                let legacy: LegacyBundle = deserialize(data)?;
+                Ok(Self {
+                    ciphertext: legacy.ciphertext,
+                    enc_key: legacy.enc_key,
+                    key_wrap: KeyWrap::RsaPkcs1,
+                    key_id: None,
+                    content_cipher: ContentCipher::Aes256Cbc,
+                    metadata: None,
+                })
+            }
+            Some([2, body @ ..]) => {
+                let v2: BundleV2 = deserialize(body)?;
+                Ok(Self {
+                    ciphertext: v2.ciphertext,
+                    enc_key: v2.enc_key,
+                    key_wrap: v2.key_wrap,
+                    key_id: v2.key_id,
+                    content_cipher: ContentCipher::Aes256Cbc,
+                    metadata: None,
+                })
+            }
+            Some([3, body @ ..]) => {
+                let v3: BundleV3 = deserialize(body)?;
+                Ok(Self {
+                    ciphertext: v3.ciphertext,
+                    enc_key: v3.enc_key,
+                    key_wrap: v3.key_wrap,
+                    key_id: v3.key_id,
+                    content_cipher: v3.content_cipher,
+                    metadata: None,
+                })
+            }
+            Some([4, body @ ..]) => {
+                let v4: BundleV4 = deserialize(body)?;
+                Ok(Self {
+                    ciphertext: v4.ciphertext,
+                    enc_key: v4.enc_key,
+                    key_wrap: v4.key_wrap,
+                    key_id: v4.key_id,
+                    content_cipher: v4.content_cipher,
+                    metadata: v4.metadata.map(|metadata| Metadata {
+                        submission_id: metadata.submission_id,
+                        created: metadata.created,
+                        sequence: None,
+                    }),
+                })
+            }
+            Some([FORMAT_VERSION, body @ ..]) => deserialize(body),
+            Some([version, ..]) => Err(Error::UnsupportedVersion(*version)),
+            Some([]) => Err(Error::Malformed("Truncated bundle header".to_string())),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let KeyWrap::Threshold(threshold_wrap) = &self.key_wrap else {
+            return Ok(());
+        };
+
+        let shares = &threshold_wrap.shares;
+        if shares.len() > MAX_SHARES {
+            return Err(Error::Malformed(format!("{} shares", shares.len())));
+        }
+        if threshold_wrap.threshold == 0 || threshold_wrap.threshold as usize > shares.len() {
+            return Err(Error::Malformed(format!(
+                "threshold {} of {} shares",
+                threshold_wrap.threshold,
+                shares.len()
+            )));
+        }
+        for (i, share) in shares.iter().enumerate() {
+            if share.index == 0 || shares[..i].iter().any(|other| other.index == share.index) {
+                return Err(Error::Malformed(format!(
+                    "invalid share index {}",
+                    share.index
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let mut data = MAGIC.to_vec();
+        data.push(FORMAT_VERSION);
+        bincode::serialize_into(&mut data, self)?;
+        Ok(data)
+    }
+
+    /// Writes to `<output_path>/<target>/<relative>`, where `relative` keeps the
+    /// subdirectory of the input so that equal file names do not collide
     pub fn write_to_path(
         &self,
-        output_path: &PathBuf,
+        output_path: &Path,
         target: &str,
-        filename: &OsStr,
+        relative: &Path,
     ) -> Result<(), anyhow::Error> {
-        let target_dir = PathBuf::from(output_path).join(target);
+        let file_path = output_path.join(target).join(relative);
+        let (Some(parent), Some(filename)) = (file_path.parent(), file_path.file_name()) else {
+            return Err(anyhow::format_err!("Invalid output path: {:?}", file_path));
+        };
 
-        match std::fs::metadata(&target_dir) {
-            Err(e) => {
-                assert_eq!(e.kind(), NotFound);
-                info!(
-                    ".. parent {} does not exist, creating",
-                    target_dir.display()
-                );
-                std::fs::create_dir(&target_dir)?;
+        match std::fs::metadata(parent) {
+            Ok(metadata) if metadata.is_dir() => (),
+            Ok(_) => {
+                return Err(anyhow::format_err!(
+                    "Output path {} is not a directory",
+                    parent.display()
+                ))
+            }
+            Err(e) if e.kind() == NotFound => {
+                info!(".. parent {} does not exist, creating", parent.display());
+                std::fs::create_dir_all(parent)
+                    .context(format!("Failed to create {}", parent.display()))?;
             }
-            Ok(f) => {
-                assert!(f.is_dir());
+            Err(e) => {
+                return Err(
+                    anyhow::Error::new(e).context(format!("Failed to access {}", parent.display()))
+                )
             }
         };
 
-        let file_path = PathBuf::from(&target_dir).join(filename);
-        //
         info!(".. output to: {}", &file_path.display());
 
+        // Renamed into place once complete, so that watchers of the output
+        // directory never see a partial bundle. `.tmp` files are ignored by them.
+        let mut temporary_name = filename.to_os_string();
+        temporary_name.push(".tmp");
+        let temporary_path = parent.join(temporary_name);
         info!(".. writing");
-        let file = File::create(file_path)?;
-        bincode::serialize_into(file, self)?;
+        let mut file = File::create(&temporary_path)
+            .context(format!("Failed to create {}", temporary_path.display()))?;
+        file.write_all(&self.to_bytes()?)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temporary_path, &file_path)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 5 bundle whose threshold shares are threshold wraps again,
+    /// `depth` levels deep
+    fn nested_threshold(depth: usize) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(FORMAT_VERSION);
+        data.extend(0u64.to_le_bytes()); // ciphertext
+        data.extend(0u64.to_le_bytes()); // enc_key
+        for _ in 0..depth {
+            data.extend(2u32.to_le_bytes()); // KeyWrap::Threshold
+            data.push(1); // threshold
+            data.extend(1u64.to_le_bytes()); // one share
+            data.push(1); // index
+            data.extend(0u64.to_le_bytes()); // key_id
+        }
+        data
+    }
+
+    #[test]
+    fn nested_threshold_is_rejected_without_recursing() {
+        // Deep enough to overflow the stack if shares could hold thresholds
+        let data = nested_threshold(200_000);
+        assert!(matches!(
+            Bundle::from_bytes(&data),
+            Err(Error::Malformed(_))
+        ));
+    }
+}
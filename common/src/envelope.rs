@@ -0,0 +1,221 @@
+//! Hybrid encryption of submissions into bundles and back.
+
+use crate::bundle::{Bundle, KeyWrap, Metadata, ThresholdWrap, WrappedShare};
+use crate::recipient::{Identity, Recipient};
+use crate::shamir::{self, Share};
+use crate::symmetric_cipher::{ContentCipher, SymmetricCipher, KEY_LENGTH};
+use std::fmt;
+use std::ops::Deref;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The bundle is not encrypted to the given identity
+    KeyMismatch(String),
+    /// The wrapped key or the ciphertext has been modified
+    Tampered,
+    UnsupportedVersion(u8),
+    /// The bundle could not be parsed
+    Malformed(String),
+    /// Encryption itself failed, for example while wrapping the key
+    Crypto(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::KeyMismatch(reason) => write!(f, "Bundle is not for this key: {}", reason),
+            Error::Tampered => write!(f, "Bundle failed authentication, it has been modified"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bundle version: {}", version)
+            }
+            Error::Malformed(reason) => write!(f, "Malformed bundle: {}", reason),
+            Error::Crypto(reason) => write!(f, "Encryption failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the bundle itself is at fault, so that it can never be opened
+    /// however often it is retried
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::KeyMismatch(_)
+            | Error::Tampered
+            | Error::UnsupportedVersion(_)
+            | Error::Malformed(_) => true,
+            Error::Crypto(_) => false,
+        }
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Malformed(err.to_string())
+    }
+}
+
+fn crypto(err: anyhow::Error) -> Error {
+    Error::Crypto(format!("{:#}", err))
+}
+
+/// Decrypted contents of a bundle
+#[derive(Debug)]
+pub struct Plaintext(Vec<u8>);
+
+impl Plaintext {
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl Deref for Plaintext {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Plaintext {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+fn encrypt_content(
+    cipher: &SymmetricCipher,
+    plaintext: &[u8],
+    key_wrap: KeyWrap,
+    enc_key: Vec<u8>,
+    key_id: Option<String>,
+    metadata: Metadata,
+) -> Result<Bundle, Error> {
+    let mut bundle = Bundle {
+        ciphertext: Vec::new(),
+        enc_key,
+        key_wrap,
+        key_id,
+        content_cipher: ContentCipher::Aes256Gcm,
+        metadata: Some(metadata),
+    };
+    bundle.ciphertext = cipher
+        .encrypt_aad(plaintext, &bundle.authenticated_data())
+        .map_err(crypto)?;
+    Ok(bundle)
+}
+
+fn decrypt_content(bundle: &Bundle, key: &[u8]) -> Result<Plaintext, Error> {
+    if key.len() != KEY_LENGTH {
+        return Err(Error::Tampered);
+    }
+    let cipher = SymmetricCipher::with_algorithm(bundle.content_cipher, Some(key));
+    cipher
+        .decrypt_aad(&bundle.ciphertext, &bundle.authenticated_data())
+        .map(Plaintext)
+        .map_err(|_| Error::Tampered)
+}
+
+/// Encrypts `plaintext` to a single recipient
+pub fn seal(plaintext: &[u8], recipient: &Recipient, metadata: Metadata) -> Result<Bundle, Error> {
+    let cipher = SymmetricCipher::with_algorithm(ContentCipher::Aes256Gcm, None);
+    let (key_wrap, enc_key) = recipient
+        .wrap_key(cipher.get_key().as_slice())
+        .map_err(crypto)?;
+    encrypt_content(
+        &cipher,
+        plaintext,
+        key_wrap,
+        enc_key,
+        Some(recipient.key_id()),
+        metadata,
+    )
+}
+
+/// Encrypts `plaintext` so that any `threshold` of `recipients` together can open it
+pub fn seal_threshold(
+    plaintext: &[u8],
+    threshold: u8,
+    recipients: &[Recipient],
+    metadata: Metadata,
+) -> Result<Bundle, Error> {
+    let count = u8::try_from(recipients.len())
+        .map_err(|_| Error::Crypto("Too many threshold recipients".to_string()))?;
+    let cipher = SymmetricCipher::with_algorithm(ContentCipher::Aes256Gcm, None);
+    let shares = shamir::split(cipher.get_key().as_slice(), threshold, count).map_err(crypto)?;
+
+    let mut wrapped_shares = Vec::with_capacity(shares.len());
+    for (share, recipient) in shares.iter().zip(recipients) {
+        let (key_wrap, enc_share) = recipient.wrap_share(&share.value).map_err(crypto)?;
+        wrapped_shares.push(WrappedShare {
+            index: share.index,
+            key_id: recipient.key_id(),
+            key_wrap,
+            enc_share,
+        });
+    }
+
+    let key_wrap = KeyWrap::Threshold(ThresholdWrap {
+        threshold,
+        shares: wrapped_shares,
+    });
+    encrypt_content(&cipher, plaintext, key_wrap, Vec::new(), None, metadata)
+}
+
+/// Decrypts a bundle that was sealed to `identity`
+pub fn open(bundle: &Bundle, identity: &Identity) -> Result<Plaintext, Error> {
+    match (&bundle.key_wrap, identity) {
+        (KeyWrap::Threshold(threshold_wrap), _) => {
+            return Err(Error::KeyMismatch(format!(
+                "{} of {} shares are needed",
+                threshold_wrap.threshold,
+                threshold_wrap.shares.len()
+            )))
+        }
+        (KeyWrap::RsaPkcs1, Identity::Rsa(_)) | (KeyWrap::HybridKem(_), Identity::Hybrid(_)) => (),
+        _ => {
+            return Err(Error::KeyMismatch(format!(
+                "{} does not match the private key type",
+                bundle.key_wrap.algorithm()
+            )))
+        }
+    }
+
+    let key_id = identity.key_id();
+    if let Some(bundle_key_id) = &bundle.key_id {
+        if *bundle_key_id != key_id {
+            return Err(Error::KeyMismatch(format!(
+                "encrypted to key {}, not {}",
+                bundle_key_id, key_id
+            )));
+        }
+    }
+
+    let key = match identity.unwrap_key(&bundle.key_wrap, &bundle.enc_key) {
+        Ok(key) => key,
+        // Without a key ID a failed unwrap most likely means a different key
+        Err(err) if bundle.key_id.is_none() => {
+            return Err(Error::KeyMismatch(format!("{:#}", err)))
+        }
+        Err(_) => return Err(Error::Tampered),
+    };
+    decrypt_content(bundle, &key)
+}
+
+/// Decrypts a threshold bundle from at least `threshold` of its decrypted shares
+pub fn open_with_shares(bundle: &Bundle, shares: &[Share]) -> Result<Plaintext, Error> {
+    let KeyWrap::Threshold(threshold_wrap) = &bundle.key_wrap else {
+        return Err(Error::KeyMismatch("not a threshold bundle".to_string()));
+    };
+    if shares.len() < threshold_wrap.threshold as usize {
+        return Err(Error::KeyMismatch(format!(
+            "{} shares given, {} needed",
+            shares.len(),
+            threshold_wrap.threshold
+        )));
+    }
+
+    let key = shamir::combine(shares).map_err(|err| Error::Malformed(format!("{:#}", err)))?;
+    decrypt_content(bundle, &key)
+}
@@ -0,0 +1,341 @@
+use crate::rsa_keys::{thumbprint, KeyFromString, KeyPolicy};
+use anyhow::Context;
+use data_encoding::BASE64URL_NOPAD;
+use kem::Decapsulate;
+use ml_kem::array::Array;
+use ml_kem::{EncapsulateDeterministic, EncodedSizeUser, KemCore, MlKem768, B32};
+use openssl::aes::{unwrap_key, wrap_key, AesKey};
+use openssl::derive::Deriver;
+use openssl::md::Md;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::rand::rand_bytes;
+use openssl::sha::Sha256;
+use serde_derive::{Deserialize, Serialize};
+
+pub const KTY: &str = "HYBRID";
+pub const ALG: &str = "X25519-ML-KEM-768";
+
+const KEK_LENGTH: usize = 32;
+const KEK_INFO: &[u8] = b"form-encryption-tools X25519-ML-KEM-768 KEK";
+
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+fn b64_decode(x: &str) -> Result<Vec<u8>, anyhow::Error> {
+    Ok(BASE64URL_NOPAD.decode(x.as_bytes())?)
+}
+
+fn random_b32() -> Result<B32, anyhow::Error> {
+    let mut bytes = B32::default();
+    rand_bytes(bytes.as_mut_slice())?;
+    Ok(bytes)
+}
+
+/// Per-bundle encapsulation stored next to the wrapped content key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HybridCapsule {
+    pub x25519_ephemeral: Vec<u8>,
+    pub mlkem_ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HybridPubkey {
+    kty: String,
+    alg: String,
+    x: String,
+    ek: String,
+}
+
+impl HybridPubkey {
+    pub fn into_recipient(self, policy: &KeyPolicy) -> Result<HybridRecipient, anyhow::Error> {
+        policy.check_kty(&self.kty)?;
+        policy.check_alg(Some(&self.alg))?;
+        if self.kty != KTY || self.alg != ALG {
+            return Err(anyhow::format_err!(
+                "Invalid keytype: {}/{}, expected {}/{}",
+                &self.kty,
+                &self.alg,
+                KTY,
+                ALG
+            ));
+        }
+        HybridRecipient::from_raw(&b64_decode(&self.x)?, &b64_decode(&self.ek)?)
+    }
+}
+
+impl KeyFromString<HybridPubkey> for HybridPubkey {
+    fn from_raw_string(data: &str) -> Result<HybridPubkey, anyhow::Error> {
+        serde_json::from_str(data).map_err(|err| err.into())
+    }
+}
+
+/// Private half of a hybrid key. The ML-KEM part is kept as its 64-byte seed.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HybridPrivateKey {
+    kty: String,
+    alg: String,
+    x: String,
+    ek: String,
+    d: String,
+    seed: String,
+}
+
+impl HybridPrivateKey {
+    pub fn generate() -> Result<Self, anyhow::Error> {
+        let x25519 = PKey::generate_x25519()?;
+        let (d, z) = (random_b32()?, random_b32()?);
+        let (_, ek) = MlKem768::generate_deterministic(&d, &z);
+
+        let mut seed = d.to_vec();
+        seed.extend_from_slice(&z);
+
+        Ok(Self {
+            kty: KTY.to_string(),
+            alg: ALG.to_string(),
+            x: BASE64URL_NOPAD.encode(&x25519.raw_public_key()?),
+            ek: BASE64URL_NOPAD.encode(&ek.as_bytes()),
+            d: BASE64URL_NOPAD.encode(&x25519.raw_private_key()?),
+            seed: BASE64URL_NOPAD.encode(&seed),
+        })
+    }
+
+    pub fn public(&self) -> HybridPubkey {
+        HybridPubkey {
+            kty: self.kty.clone(),
+            alg: self.alg.clone(),
+            x: self.x.clone(),
+            ek: self.ek.clone(),
+        }
+    }
+
+    pub fn into_identity(self) -> Result<HybridIdentity, anyhow::Error> {
+        if self.kty != KTY || self.alg != ALG {
+            return Err(anyhow::format_err!(
+                "Invalid keytype: {}/{}, expected {}/{}",
+                &self.kty,
+                &self.alg,
+                KTY,
+                ALG
+            ));
+        }
+
+        let seed = b64_decode(&self.seed)?;
+        if seed.len() != 64 {
+            return Err(anyhow::format_err!(
+                "Invalid ML-KEM seed length: {}, expected 64",
+                seed.len()
+            ));
+        }
+        let d = B32::try_from(&seed[..32]).expect("Seed half is 32 bytes");
+        let z = B32::try_from(&seed[32..]).expect("Seed half is 32 bytes");
+        let (dk, ek) = MlKem768::generate_deterministic(&d, &z);
+
+        let recipient = HybridRecipient::from_raw(&b64_decode(&self.x)?, &b64_decode(&self.ek)?)?;
+        if *recipient.ek != ek {
+            return Err(anyhow::Error::msg(
+                "ML-KEM seed does not match the public key",
+            ));
+        }
+
+        let x25519 = PKey::private_key_from_raw_bytes(&b64_decode(&self.d)?, Id::X25519)
+            .context("Building X25519 private key")?;
+        if x25519.raw_public_key()? != recipient.x25519_raw {
+            return Err(anyhow::Error::msg(
+                "X25519 private key does not match the public key",
+            ));
+        }
+
+        Ok(HybridIdentity {
+            x25519,
+            dk: Box::new(dk),
+            recipient,
+        })
+    }
+}
+
+impl KeyFromString<HybridPrivateKey> for HybridPrivateKey {
+    fn from_raw_string(data: &str) -> Result<HybridPrivateKey, anyhow::Error> {
+        serde_json::from_str(data).map_err(|err| err.into())
+    }
+}
+
+pub struct HybridRecipient {
+    x25519: PKey<Public>,
+    x25519_raw: Vec<u8>,
+    ek: Box<EncapsulationKey>,
+}
+
+impl HybridRecipient {
+    fn from_raw(x25519_raw: &[u8], ek_raw: &[u8]) -> Result<Self, anyhow::Error> {
+        let x25519 = PKey::public_key_from_raw_bytes(x25519_raw, Id::X25519)
+            .context("Building X25519 public key")?;
+        let ek_raw = Array::try_from(ek_raw).map_err(|_| {
+            anyhow::format_err!("Invalid ML-KEM-768 public key length: {}", ek_raw.len())
+        })?;
+        Ok(Self {
+            x25519,
+            x25519_raw: x25519_raw.to_vec(),
+            ek: Box::new(EncapsulationKey::from_bytes(&ek_raw)),
+        })
+    }
+
+    pub fn key_id(&self) -> String {
+        thumbprint(&[
+            ("ek", &BASE64URL_NOPAD.encode(&self.ek.as_bytes())),
+            ("kty", KTY),
+            ("x", &BASE64URL_NOPAD.encode(&self.x25519_raw)),
+        ])
+    }
+
+    /// Wraps `key` with a key-encryption key derived from a fresh encapsulation
+    pub fn wrap_key(&self, key: &[u8]) -> Result<(HybridCapsule, Vec<u8>), anyhow::Error> {
+        let ephemeral = PKey::generate_x25519()?;
+        let mut deriver = Deriver::new(&ephemeral)?;
+        deriver.set_peer(&self.x25519)?;
+        let x25519_secret = deriver.derive_to_vec()?;
+
+        let (mlkem_ciphertext, mlkem_secret) = self
+            .ek
+            .encapsulate_deterministic(&random_b32()?)
+            .map_err(|_| anyhow::Error::msg("ML-KEM encapsulation failed"))?;
+
+        let capsule = HybridCapsule {
+            x25519_ephemeral: ephemeral.raw_public_key()?,
+            mlkem_ciphertext: mlkem_ciphertext.to_vec(),
+        };
+        let kek = combine(&mlkem_secret, &x25519_secret, &capsule, &self.x25519_raw)?;
+
+        let kek = AesKey::new_encrypt(&kek)
+            .map_err(|_| anyhow::Error::msg("Invalid key-encryption key"))?;
+        let mut wrapped = vec![0u8; key.len() + 8];
+        wrap_key(&kek, None, &mut wrapped, key)
+            .map_err(|_| anyhow::Error::msg("Key wrapping failed"))?;
+
+        Ok((capsule, wrapped))
+    }
+}
+
+pub struct HybridIdentity {
+    x25519: PKey<Private>,
+    dk: Box<DecapsulationKey>,
+    recipient: HybridRecipient,
+}
+
+impl HybridIdentity {
+    pub fn key_id(&self) -> String {
+        self.recipient.key_id()
+    }
+
+    pub fn unwrap_key(
+        &self,
+        capsule: &HybridCapsule,
+        wrapped: &[u8],
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let ephemeral = PKey::public_key_from_raw_bytes(&capsule.x25519_ephemeral, Id::X25519)
+            .context("Building ephemeral X25519 key")?;
+        let mut deriver = Deriver::new(&self.x25519)?;
+        deriver.set_peer(&ephemeral)?;
+        let x25519_secret = deriver.derive_to_vec()?;
+
+        let mlkem_ciphertext = Array::try_from(capsule.mlkem_ciphertext.as_slice())
+            .map_err(|_| anyhow::Error::msg("Invalid ML-KEM-768 ciphertext length"))?;
+        let mlkem_secret = self
+            .dk
+            .decapsulate(&mlkem_ciphertext)
+            .map_err(|_| anyhow::Error::msg("ML-KEM decapsulation failed"))?;
+
+        let kek = combine(
+            &mlkem_secret,
+            &x25519_secret,
+            capsule,
+            &self.recipient.x25519_raw,
+        )?;
+
+        if wrapped.len() < 16 {
+            return Err(anyhow::Error::msg("Wrapped key is too short"));
+        }
+        let kek = AesKey::new_decrypt(&kek)
+            .map_err(|_| anyhow::Error::msg("Invalid key-encryption key"))?;
+        let mut key = vec![0u8; wrapped.len() - 8];
+        unwrap_key(&kek, None, &mut key, wrapped).map_err(|_| {
+            anyhow::Error::msg("Key unwrapping failed, wrong key or tampered bundle")
+        })?;
+        Ok(key)
+    }
+}
+
+/// Derives the key-encryption key from both shared secrets, bound to the
+/// encapsulations and the recipient's X25519 key so neither half can be swapped out.
+fn combine(
+    mlkem_secret: &[u8],
+    x25519_secret: &[u8],
+    capsule: &HybridCapsule,
+    recipient_x25519: &[u8],
+) -> Result<[u8; KEK_LENGTH], anyhow::Error> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(&[mlkem_secret, x25519_secret].concat())?;
+    ctx.add_hkdf_info(KEK_INFO)?;
+
+    // The ML-KEM ciphertext alone is over the 1 KiB info limit of older OpenSSL
+    let mut transcript = Sha256::new();
+    transcript.update(&capsule.mlkem_ciphertext);
+    transcript.update(&capsule.x25519_ephemeral);
+    transcript.update(recipient_x25519);
+    ctx.add_hkdf_info(&transcript.finish())?;
+
+    let mut kek = [0u8; KEK_LENGTH];
+    ctx.derive(Some(&mut kek))?;
+    Ok(kek)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_KEY: &[u8; 32] = b"a content key of thirty-two byte";
+
+    /// Through the JSON forms, as the tools store keys
+    fn key_pair() -> (HybridIdentity, HybridRecipient) {
+        let private = HybridPrivateKey::generate().unwrap();
+        let public = serde_json::to_string(&private.public()).unwrap();
+        let recipient = HybridPubkey::from_raw_string(&public)
+            .unwrap()
+            .into_recipient(&KeyPolicy::default())
+            .unwrap();
+        let private = serde_json::to_string(&private).unwrap();
+        let identity = HybridPrivateKey::from_raw_string(&private)
+            .unwrap()
+            .into_identity()
+            .unwrap();
+        (identity, recipient)
+    }
+
+    #[test]
+    fn wrapped_keys_unwrap() {
+        let (identity, recipient) = key_pair();
+        assert_eq!(identity.key_id(), recipient.key_id());
+        let (capsule, wrapped) = recipient.wrap_key(CONTENT_KEY).unwrap();
+        assert_eq!(
+            identity.unwrap_key(&capsule, &wrapped).unwrap(),
+            CONTENT_KEY
+        );
+    }
+
+    #[test]
+    fn other_keys_and_swapped_capsules_do_not_unwrap() {
+        let (identity, recipient) = key_pair();
+        let (other, _) = key_pair();
+        let (capsule, wrapped) = recipient.wrap_key(CONTENT_KEY).unwrap();
+        assert!(other.unwrap_key(&capsule, &wrapped).is_err());
+
+        let (second, _) = recipient.wrap_key(CONTENT_KEY).unwrap();
+        let swapped = HybridCapsule {
+            x25519_ephemeral: capsule.x25519_ephemeral.clone(),
+            mlkem_ciphertext: second.mlkem_ciphertext,
+        };
+        assert!(identity.unwrap_key(&swapped, &wrapped).is_err());
+    }
+}
@@ -1,7 +1,16 @@
 extern crate core;
 
 pub mod bundle;
+pub mod envelope;
+pub mod hybrid_kem;
+pub mod maildir;
+pub mod recipient;
 pub mod rsa_keys;
+mod s3;
+pub mod shamir;
+pub mod sinks;
 pub mod sources;
 pub mod symmetric_cipher;
 pub mod watch;
+
+pub use envelope::{open, open_with_shares, seal, seal_threshold, Error, Plaintext};
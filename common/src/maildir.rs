@@ -0,0 +1,110 @@
+//! Maildir folders as used by local MTAs, see <https://cr.yp.to/proto/maildir.html>
+//!
+//! Messages are written to `tmp/` and renamed into `new/` once complete, mail
+//! clients move them on to `cur/` with flags appended to their names.
+
+use crate::sources::claim;
+use anyhow::{Context, Error};
+use log::info;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Deliveries by this process, to keep names unique within the same microsecond
+static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+
+pub struct Maildir {
+    path: PathBuf,
+}
+
+impl Maildir {
+    /// Opens the Maildir at `path`, creating `tmp/`, `new/` and `cur/` as needed
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        for subdirectory in ["tmp", "new", "cur"] {
+            let directory = path.join(subdirectory);
+            fs::create_dir_all(&directory).context(format!(
+                "Failed to create Maildir directory {:?}",
+                directory
+            ))?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Opens an existing Maildir, without creating anything
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        for subdirectory in ["tmp", "new", "cur"] {
+            let directory = path.join(subdirectory);
+            if !directory.is_dir() {
+                return Err(anyhow::format_err!(
+                    "{:?} is not a Maildir, {:?} is missing",
+                    path,
+                    directory
+                ));
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes a complete message into `new/`, returns its path
+    pub fn deliver(&self, message: &[u8]) -> Result<PathBuf, Error> {
+        let name = unique_name();
+        let temporary = self.path.join("tmp").join(&name);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temporary)
+            .context(format!("Failed to create {:?}", temporary))?;
+        file.write_all(message)?;
+        file.sync_all()?;
+        drop(file);
+
+        let delivered = self.path.join("new").join(&name);
+        if let Err(e) = fs::rename(&temporary, &delivered) {
+            let _ = fs::remove_file(&temporary);
+            return Err(Error::new(e).context(format!("Failed to deliver {:?}", delivered)));
+        }
+        info!("Delivered {:?}", delivered);
+        Ok(delivered)
+    }
+}
+
+/// `<seconds>.M<microseconds>P<pid>Q<count>.<hostname>`
+fn unique_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        hostname()
+    )
+}
+
+/// With `/` and `:` escaped as the Maildir specification asks
+fn hostname() -> String {
+    claim::hostname()
+        .replace('/', "\\057")
+        .replace(':', "\\072")
+}
+
+/// The name of a message in `cur/`, keeping its flags or marking it seen
+pub fn seen_name(name: &str) -> String {
+    if name.contains(":2,") {
+        name.to_string()
+    } else {
+        format!("{}:2,S", name)
+    }
+}
@@ -0,0 +1,139 @@
+use crate::bundle::{KeyWrap, ShareWrap};
+use crate::hybrid_kem::{self, HybridIdentity, HybridPrivateKey, HybridPubkey, HybridRecipient};
+use crate::rsa_keys::{rsa_key_id, KeyFromString, KeyPolicy, RsaPubkey};
+use anyhow::Context;
+use openssl::pkey::{Private, Public};
+use openssl::rsa::{Padding, Rsa};
+use serde_derive::Deserialize;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct KeyType {
+    kty: String,
+}
+
+/// A public JWK of any of the supported key types
+pub enum PublicJwk {
+    Rsa(RsaPubkey),
+    Hybrid(HybridPubkey),
+}
+
+impl KeyFromString<PublicJwk> for PublicJwk {
+    fn from_raw_string(data: &str) -> Result<PublicJwk, anyhow::Error> {
+        let key_type: KeyType = serde_json::from_str(data)?;
+        match key_type.kty.as_str() {
+            "RSA" => Ok(PublicJwk::Rsa(RsaPubkey::from_raw_string(data)?)),
+            hybrid_kem::KTY => Ok(PublicJwk::Hybrid(HybridPubkey::from_raw_string(data)?)),
+            kty => Err(anyhow::format_err!("Unsupported keytype: {}", kty)),
+        }
+    }
+}
+
+impl PublicJwk {
+    pub fn into_recipient(self, policy: &KeyPolicy) -> Result<Recipient, anyhow::Error> {
+        Ok(match self {
+            PublicJwk::Rsa(key) => Recipient::Rsa(key.into_rsa_key_with_policy(policy)?),
+            PublicJwk::Hybrid(key) => Recipient::Hybrid(key.into_recipient(policy)?),
+        })
+    }
+}
+
+/// A key that bundles can be encrypted to
+pub enum Recipient {
+    Rsa(Rsa<Public>),
+    Hybrid(HybridRecipient),
+}
+
+impl Recipient {
+    pub fn key_id(&self) -> String {
+        match self {
+            Recipient::Rsa(key) => rsa_key_id(key),
+            Recipient::Hybrid(key) => key.key_id(),
+        }
+    }
+
+    pub fn wrap_key(&self, key: &[u8]) -> Result<(KeyWrap, Vec<u8>), anyhow::Error> {
+        let (share_wrap, wrapped_key) = self.wrap_share(key)?;
+        Ok((share_wrap.into(), wrapped_key))
+    }
+
+    /// Wraps a key or a share of one, which is never split itself
+    pub fn wrap_share(&self, key: &[u8]) -> Result<(ShareWrap, Vec<u8>), anyhow::Error> {
+        match self {
+            Recipient::Rsa(rsa_key) => {
+                let mut wrapped_key = vec![0; rsa_key.size() as usize];
+                rsa_key.public_encrypt(key, wrapped_key.as_mut_slice(), Padding::PKCS1)?;
+                Ok((ShareWrap::RsaPkcs1, wrapped_key))
+            }
+            Recipient::Hybrid(hybrid_key) => {
+                let (capsule, wrapped_key) = hybrid_key.wrap_key(key)?;
+                Ok((ShareWrap::HybridKem(capsule), wrapped_key))
+            }
+        }
+    }
+}
+
+/// A private key that bundles can be decrypted with
+pub enum Identity {
+    Rsa(Rsa<Private>),
+    Hybrid(HybridIdentity),
+}
+
+impl Identity {
+    /// Loads either a PEM encoded RSA key or a hybrid private JWK
+    pub fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let data = fs::read(path).context(format!("Error reading private key: {:?}", path))?;
+        if data.starts_with(b"-----BEGIN") {
+            Ok(Identity::Rsa(Rsa::private_key_from_pem(&data)?))
+        } else {
+            let data = String::from_utf8(data).context("Private key is not valid UTF-8")?;
+            Ok(Identity::Hybrid(
+                HybridPrivateKey::from_raw_string(&data)?.into_identity()?,
+            ))
+        }
+    }
+
+    pub fn key_id(&self) -> String {
+        match self {
+            Identity::Rsa(key) => rsa_key_id(key),
+            Identity::Hybrid(key) => key.key_id(),
+        }
+    }
+
+    pub fn unwrap_key(
+        &self,
+        key_wrap: &KeyWrap,
+        wrapped_key: &[u8],
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        match (key_wrap, self) {
+            (KeyWrap::RsaPkcs1, Identity::Rsa(rsa_key)) => {
+                let mut key = vec![0; rsa_key.size() as usize];
+                let len = rsa_key
+                    .private_decrypt(wrapped_key, key.as_mut_slice(), Padding::PKCS1)
+                    .context("RSA decryption of the wrapped key failed")?;
+                key.truncate(len);
+                Ok(key)
+            }
+            (KeyWrap::HybridKem(capsule), Identity::Hybrid(hybrid_key)) => {
+                hybrid_key.unwrap_key(capsule, wrapped_key)
+            }
+            (KeyWrap::Threshold(threshold_wrap), _) => Err(anyhow::format_err!(
+                "Key is split into {} shares, {} partial decryptions are needed",
+                threshold_wrap.shares.len(),
+                threshold_wrap.threshold
+            )),
+            _ => Err(anyhow::Error::msg(
+                "Key wrapping does not match the private key type",
+            )),
+        }
+    }
+
+    pub fn unwrap_share(
+        &self,
+        share_wrap: &ShareWrap,
+        wrapped_share: &[u8],
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        self.unwrap_key(&share_wrap.clone().into(), wrapped_share)
+    }
+}
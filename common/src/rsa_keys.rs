@@ -1,12 +1,20 @@
+use crate::hybrid_kem;
 use anyhow::Context;
 use data_encoding::BASE64URL_NOPAD;
 use log::info;
 use openssl::bn::{BigNum, BigNumRef};
 use openssl::pkey;
-use openssl::rsa::Rsa;
+use openssl::pkey::HasPublic;
+use openssl::rsa::{Rsa, RsaRef};
+use openssl::sha::sha256;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
 use std::io::Read;
 
+/// Keys are fetched from URLs, so bound how much of the response is read
+const MAX_KEY_SIZE: u64 = 64 * 1024;
+
 pub fn encode(x: &BigNumRef) -> String {
     let raw = x.to_vec();
     BASE64URL_NOPAD.encode(&raw)
@@ -18,6 +26,21 @@ pub fn decode(x: &str) -> Result<BigNum, anyhow::Error> {
     Ok(bn)
 }
 
+/// JWK thumbprint (RFC 7638) over the required members of a key
+pub fn thumbprint(members: &[(&str, &str)]) -> String {
+    let canonical: BTreeMap<&str, &str> = members.iter().copied().collect();
+    let json = serde_json::to_string(&canonical).expect("Serializing string map cannot fail");
+    BASE64URL_NOPAD.encode(&sha256(json.as_bytes()))
+}
+
+pub fn rsa_key_id<T: HasPublic>(key: &RsaRef<T>) -> String {
+    thumbprint(&[
+        ("e", &encode(key.e())),
+        ("kty", "RSA"),
+        ("n", &encode(key.n())),
+    ])
+}
+
 pub trait KeyFromString<T> {
     fn from_raw_string(data: &str) -> Result<T, anyhow::Error>;
 }
@@ -33,22 +56,144 @@ where
     fn from_url(url: &str) -> Result<T, anyhow::Error> {
         info!("Fetching {}", url);
         let mut keyfile_string = String::new();
-        reqwest::blocking::get(url)?.read_to_string(&mut keyfile_string)?;
+        reqwest::blocking::get(url)?
+            .take(MAX_KEY_SIZE + 1)
+            .read_to_string(&mut keyfile_string)?;
+        if keyfile_string.len() as u64 > MAX_KEY_SIZE {
+            return Err(anyhow::format_err!(
+                "Key at {} is larger than {} bytes",
+                url,
+                MAX_KEY_SIZE
+            ));
+        }
 
         Self::from_raw_string(&keyfile_string)
     }
 }
 
+/// Restrictions a fetched public key has to satisfy before anything is encrypted to it.
+///
+/// A key without an `alg` member is accepted as long as its `kty` is allowed, since
+/// keys produced by older versions of `convert_key` do not carry one, unless
+/// `require_alg` is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KeyPolicy {
+    pub min_rsa_bits: u32,
+    pub allowed_exponents: Vec<u32>,
+    pub allowed_kty: Vec<String>,
+    pub allowed_alg: Vec<String>,
+    pub require_alg: bool,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            min_rsa_bits: 2048,
+            allowed_exponents: vec![65537],
+            allowed_kty: vec!["RSA".to_string(), hybrid_kem::KTY.to_string()],
+            allowed_alg: vec!["RSA1_5".to_string(), hybrid_kem::ALG.to_string()],
+            require_alg: false,
+        }
+    }
+}
+
+fn join<T: Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl KeyPolicy {
+    pub fn check_kty(&self, kty: &str) -> Result<(), anyhow::Error> {
+        if !self.allowed_kty.iter().any(|allowed| allowed == kty) {
+            return Err(anyhow::format_err!(
+                "Key policy violation: kty {} is not allowed (allowed: {})",
+                kty,
+                join(&self.allowed_kty)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_alg(&self, alg: Option<&str>) -> Result<(), anyhow::Error> {
+        match alg {
+            Some(alg) if !self.allowed_alg.iter().any(|allowed| allowed == alg) => {
+                Err(anyhow::format_err!(
+                    "Key policy violation: alg {} is not allowed (allowed: {})",
+                    alg,
+                    join(&self.allowed_alg)
+                ))
+            }
+            None if self.require_alg => Err(anyhow::Error::msg(
+                "Key policy violation: key has no alg, which is required",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_rsa(&self, key: &Rsa<pkey::Public>) -> Result<(), anyhow::Error> {
+        let bits = key.n().num_bits() as u32;
+        if bits < self.min_rsa_bits {
+            return Err(anyhow::format_err!(
+                "Key policy violation: RSA modulus is {} bits, at least {} required",
+                bits,
+                self.min_rsa_bits
+            ));
+        }
+
+        let e = key.e();
+        let allowed = self
+            .allowed_exponents
+            .iter()
+            .map(|allowed| BigNum::from_u32(*allowed))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !allowed.iter().any(|allowed| allowed.as_ref() == e) {
+            return Err(anyhow::format_err!(
+                "Key policy violation: RSA exponent {} is not allowed (allowed: {})",
+                e.to_dec_str()?,
+                join(&self.allowed_exponents)
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RsaPubkey {
     kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alg: Option<String>,
     n: String,
     e: String,
 }
 
 impl RsaPubkey {
     pub fn from_parts(kty: String, n: String, e: String) -> Self {
-        Self { kty, n, e }
+        Self {
+            kty,
+            alg: None,
+            n,
+            e,
+        }
+    }
+
+    pub fn with_alg(mut self, alg: &str) -> Self {
+        self.alg = Some(alg.to_string());
+        self
+    }
+
+    pub fn into_rsa_key_with_policy(
+        self,
+        policy: &KeyPolicy,
+    ) -> Result<Rsa<pkey::Public>, anyhow::Error> {
+        policy.check_kty(&self.kty)?;
+        policy.check_alg(self.alg.as_deref())?;
+        let rsa_key = self.into_rsa_key()?;
+        policy.check_rsa(&rsa_key)?;
+        Ok(rsa_key)
     }
 
     pub fn into_rsa_key(self) -> Result<Rsa<pkey::Public>, anyhow::Error> {
@@ -102,8 +247,7 @@ impl RsaPrivateKey {
             decode(&self.dmq1)?,
             decode(&self.iqmp)?,
         )
-        .context("Building RSA key from components")
-        .unwrap();
+        .context("Building RSA key from components")?;
         Ok(rsa_key)
     }
 }
@@ -113,3 +257,63 @@ impl KeyFromString<RsaPrivateKey> for RsaPrivateKey {
         serde_json::from_str(data).map_err(|err| err.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The public JWK of a new RSA key
+    fn jwk(bits: u32, exponent: u32) -> RsaPubkey {
+        let key = Rsa::generate_with_e(bits, &BigNum::from_u32(exponent).unwrap()).unwrap();
+        RsaPubkey::from_parts("RSA".to_string(), encode(key.n()), encode(key.e()))
+    }
+
+    #[test]
+    fn rsa_keys_need_enough_bits_and_an_allowed_exponent() {
+        let policy = KeyPolicy::default();
+        assert!(jwk(2048, 65537).into_rsa_key_with_policy(&policy).is_ok());
+        let error = jwk(1024, 65537)
+            .into_rsa_key_with_policy(&policy)
+            .unwrap_err();
+        assert!(error.to_string().contains("1024 bits"));
+        let error = jwk(2048, 3).into_rsa_key_with_policy(&policy).unwrap_err();
+        assert!(error.to_string().contains("exponent 3"));
+    }
+
+    #[test]
+    fn kty_and_alg_must_be_allowed() {
+        let policy = KeyPolicy::default();
+        assert!(policy.check_kty("RSA").is_ok());
+        assert!(policy.check_kty("EC").is_err());
+        assert!(policy.check_alg(Some("RSA1_5")).is_ok());
+        assert!(policy.check_alg(Some("RSA-OAEP")).is_err());
+        assert!(jwk(2048, 65537)
+            .with_alg("RS256")
+            .into_rsa_key_with_policy(&policy)
+            .is_err());
+    }
+
+    #[test]
+    fn keys_without_alg_pass_unless_it_is_required() {
+        let policy = KeyPolicy::default();
+        assert!(policy.check_alg(None).is_ok());
+        let policy = KeyPolicy {
+            require_alg: true,
+            ..KeyPolicy::default()
+        };
+        assert!(policy.check_alg(None).is_err());
+        assert!(jwk(2048, 65537).into_rsa_key_with_policy(&policy).is_err());
+        assert!(jwk(2048, 65537)
+            .with_alg("RSA1_5")
+            .into_rsa_key_with_policy(&policy)
+            .is_ok());
+    }
+
+    #[test]
+    fn unset_settings_keep_their_defaults() {
+        let policy: KeyPolicy = toml::from_str("min_rsa_bits = 3072\nrequire_alg = true").unwrap();
+        assert_eq!(policy.min_rsa_bits, 3072);
+        assert!(policy.require_alg);
+        assert_eq!(policy.allowed_exponents, [65537]);
+    }
+}
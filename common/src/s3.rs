@@ -0,0 +1,585 @@
+//! The few S3 operations the queue needs, signed with AWS Signature Version 4.
+//! Works with S3 itself and with compatible stores such as MinIO.
+
+use anyhow::{Context, Error};
+use log::info;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::blocking::{Client, Response};
+use reqwest::{Method, StatusCode};
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Read;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use url::Url;
+
+/// Characters that SigV4 leaves unencoded, `/` is encoded in query parameters only
+const QUERY_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+const PATH_ENCODE: &AsciiSet = &QUERY_ENCODE.remove(b'/');
+
+const DEFAULT_REGION: &str = "us-east-1";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const METADATA_ADDRESS: &str = "http://169.254.169.254/latest";
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+/// Instance credentials are replaced five minutes before they expire
+const CREDENTIALS_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Bucket settings given as query parameters of an `s3://bucket/prefix` URL
+#[derive(Debug, Default, Clone)]
+pub struct BucketOptions {
+    /// `$AWS_REGION` or `us-east-1` by default
+    region: Option<String>,
+    /// Address of an S3-compatible store such as `http://localhost:9000`,
+    /// addressed path-style. AWS by default.
+    endpoint: Option<Url>,
+}
+
+impl BucketOptions {
+    /// Takes the option if it is a bucket option, returns whether it was
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, Error> {
+        match key {
+            "region" => self.region = Some(value.to_string()),
+            "endpoint" => self.endpoint = Some(Url::parse(value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// `incoming` and `/incoming/` to `incoming/`, so that a prefix only matches
+/// whole path segments. The empty prefix is the whole bucket.
+pub fn directory_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Bucket name and key prefix of `s3://bucket/prefix`
+pub fn bucket_and_prefix(url: &Url) -> Result<(String, String), Error> {
+    let name = url
+        .host_str()
+        .ok_or_else(|| anyhow::format_err!("No bucket in {}", url))?;
+    let prefix = percent_decode_str(url.path()).decode_utf8()?;
+    Ok((name.to_string(), directory_prefix(&prefix)))
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// Access keys from `$AWS_ACCESS_KEY_ID` and `$AWS_SECRET_ACCESS_KEY`, or else
+/// the instance profile of the EC2 instance we run on
+enum CredentialSource {
+    Static(Credentials),
+    /// With the time to replace them
+    Instance(Option<(Credentials, OffsetDateTime)>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InstanceCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    /// RFC 3339
+    expiration: String,
+}
+
+impl CredentialSource {
+    fn from_env() -> Self {
+        match (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key), Ok(secret_key)) => CredentialSource::Static(Credentials {
+                access_key,
+                secret_key,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            }),
+            _ => CredentialSource::Instance(None),
+        }
+    }
+
+    fn get(&mut self) -> Result<Credentials, Error> {
+        match self {
+            CredentialSource::Static(credentials) => Ok(credentials.clone()),
+            CredentialSource::Instance(Some((credentials, refresh)))
+                if OffsetDateTime::now_utc() < *refresh =>
+            {
+                Ok(credentials.clone())
+            }
+            CredentialSource::Instance(cached) => {
+                let (credentials, expiration) = instance_credentials()
+                    .context("No access keys set and no instance profile available")?;
+                *cached = Some((credentials.clone(), expiration - CREDENTIALS_REFRESH_MARGIN));
+                Ok(credentials)
+            }
+        }
+    }
+}
+
+/// Fetches the credentials of the instance profile with IMDSv2, and when they expire
+fn instance_credentials() -> Result<(Credentials, OffsetDateTime), Error> {
+    let client = Client::builder().timeout(METADATA_TIMEOUT).build()?;
+    let token = client
+        .put(format!("{}/api/token", METADATA_ADDRESS))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .send()?
+        .error_for_status()?
+        .text()?;
+    let get = |path: &str| -> Result<Response, Error> {
+        Ok(client
+            .get(format!(
+                "{}/meta-data/iam/security-credentials/{}",
+                METADATA_ADDRESS, path
+            ))
+            .header("X-aws-ec2-metadata-token", &token)
+            .send()?
+            .error_for_status()?)
+    };
+    let role = get("")?.text()?;
+    let role = role.lines().next().context("No instance profile role")?;
+    let credentials: InstanceCredentials = serde_json::from_slice(&get(role)?.bytes()?)?;
+    let expiration = OffsetDateTime::parse(&credentials.expiration, &Rfc3339)
+        .context(format!("Invalid expiration {}", credentials.expiration))?;
+    info!(
+        "Using credentials of instance profile role {}, expiring {}",
+        role, credentials.expiration
+    );
+    Ok((
+        Credentials {
+            access_key: credentials.access_key_id,
+            secret_key: credentials.secret_access_key,
+            session_token: Some(credentials.token),
+        },
+        expiration,
+    ))
+}
+
+fn hmac(key: &[u8], data: &str) -> Result<Vec<u8>, Error> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data.as_bytes())?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Encoded parameters, ordered by name and then value
+fn canonical_query(query: &[(&str, String)]) -> String {
+    let mut query: Vec<_> = query
+        .iter()
+        .map(|(key, value)| {
+            (
+                utf8_percent_encode(key, QUERY_ENCODE).to_string(),
+                utf8_percent_encode(value, QUERY_ENCODE).to_string(),
+            )
+        })
+        .collect();
+    query.sort();
+    query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The canonical request and the signed header names, for headers with
+/// lowercase names
+fn canonical_request(
+    method: &Method,
+    path: &str,
+    query: &str,
+    headers: &BTreeMap<String, String>,
+    payload_hash: &str,
+) -> (String, String) {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers.keys().cloned().collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, path, query, canonical_headers, signed_headers, payload_hash
+    );
+    (canonical_request, signed_headers)
+}
+
+fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(sha256(canonical_request.as_bytes()))
+    )
+}
+
+/// With the signing key derived from the parts of `date/region/service/aws4_request`
+fn signature(secret_key: &str, scope: &str, string_to_sign: &str) -> Result<String, Error> {
+    let mut key = format!("AWS4{}", secret_key).into_bytes();
+    for part in scope.split('/') {
+        key = hmac(&key, part)?;
+    }
+    Ok(hex::encode(hmac(&key, string_to_sign)?))
+}
+
+/// An object in a listing
+pub struct Object {
+    pub key: String,
+    /// RFC 3339 in UTC, which sorts chronologically as a string
+    pub last_modified: String,
+    /// In bytes
+    pub size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<ListedObject>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+    key: String,
+    last_modified: String,
+    size: u64,
+}
+
+pub struct Bucket {
+    client: Client,
+    name: String,
+    region: String,
+    endpoint: Option<Url>,
+    credentials: CredentialSource,
+}
+
+impl Bucket {
+    pub fn new(name: &str, options: BucketOptions) -> Result<Self, Error> {
+        let region = options
+            .region
+            .or_else(|| env::var("AWS_REGION").ok())
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        Ok(Self {
+            client: Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+            name: name.to_string(),
+            region,
+            endpoint: options.endpoint,
+            credentials: CredentialSource::from_env(),
+        })
+    }
+
+    /// Objects with keys starting with `prefix`, in key order
+    pub fn list(&mut self, prefix: &str) -> Result<Vec<Object>, Error> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_string()),
+                ("prefix", prefix.to_string()),
+            ];
+            if let Some(token) = continuation_token.take() {
+                query.push(("continuation-token", token));
+            }
+            let response = self.send(Method::GET, "", &query, &[], Vec::new())?;
+            let result: ListBucketResult = quick_xml::de::from_str(&checked(response)?.text()?)
+                .context("Invalid bucket listing")?;
+            objects.extend(result.contents.into_iter().map(|object| Object {
+                key: object.key,
+                last_modified: object.last_modified,
+                size: object.size,
+            }));
+            match result.next_continuation_token {
+                Some(token) if result.is_truncated => continuation_token = Some(token),
+                _ => return Ok(objects),
+            }
+        }
+    }
+
+    /// `None` if there is no such object. Objects over `max_size` bytes are an
+    /// error, without reading more than that.
+    pub fn get(&mut self, key: &str, max_size: usize) -> Result<Option<Vec<u8>>, Error> {
+        let response = self.send(Method::GET, key, &[], &[], Vec::new())?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = checked(response)?;
+        let too_large = || anyhow::format_err!("{} is larger than {} bytes", key, max_size);
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_size as u64)
+        {
+            return Err(too_large());
+        }
+        let mut contents = Vec::new();
+        response
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut contents)?;
+        if contents.len() > max_size {
+            return Err(too_large());
+        }
+        Ok(Some(contents))
+    }
+
+    pub fn put(&mut self, key: &str, contents: Vec<u8>) -> Result<(), Error> {
+        let response = self.send(Method::PUT, key, &[], &[], contents)?;
+        checked(response)?;
+        Ok(())
+    }
+
+    /// Deleting a missing object is not an error in S3
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        let response = self.send(Method::DELETE, key, &[], &[], Vec::new())?;
+        checked(response)?;
+        Ok(())
+    }
+
+    pub fn copy(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let source = format!("/{}/{}", self.name, utf8_percent_encode(from, PATH_ENCODE));
+        let response = self.send(
+            Method::PUT,
+            to,
+            &[],
+            &[("x-amz-copy-source", source)],
+            Vec::new(),
+        )?;
+        checked(response)?;
+        Ok(())
+    }
+
+    /// Path-style for custom endpoints, virtual-hosted for AWS
+    fn object_url(&self, key: &str) -> String {
+        let key = utf8_percent_encode(key, PATH_ENCODE);
+        match &self.endpoint {
+            Some(endpoint) => format!(
+                "{}/{}/{}",
+                endpoint.as_str().trim_end_matches('/'),
+                self.name,
+                key
+            ),
+            None => format!(
+                "https://{}.s3.{}.amazonaws.com/{}",
+                self.name, self.region, key
+            ),
+        }
+    }
+
+    fn send(
+        &mut self,
+        method: Method,
+        key: &str,
+        query: &[(&str, String)],
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let credentials = self.credentials.get()?;
+        let query = canonical_query(query);
+        let mut url = Url::parse(&self.object_url(key))?;
+        if !query.is_empty() {
+            url.set_query(Some(&query));
+        }
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(anyhow::format_err!("No host in {}", url)),
+        };
+        let amz_date = OffsetDateTime::now_utc().format(format_description!(
+            "[year][month][day]T[hour][minute][second]Z"
+        ))?;
+        let date = &amz_date[..8];
+        let payload_hash = hex::encode(sha256(&body));
+
+        let mut headers = BTreeMap::new();
+        headers.insert("host".to_string(), host);
+        headers.insert("x-amz-content-sha256".to_string(), payload_hash.clone());
+        headers.insert("x-amz-date".to_string(), amz_date.clone());
+        if let Some(token) = &credentials.session_token {
+            headers.insert("x-amz-security-token".to_string(), token.clone());
+        }
+        for (name, value) in extra_headers {
+            headers.insert(name.to_string(), value.clone());
+        }
+
+        let (canonical_request, signed_headers) =
+            canonical_request(&method, url.path(), &query, &headers, &payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = string_to_sign(&amz_date, &scope, &canonical_request);
+        let signature = signature(&credentials.secret_key, &scope, &string_to_sign)?;
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = self
+            .client
+            .request(method.clone(), url.clone())
+            .header("Authorization", authorization)
+            .body(body);
+        for (name, value) in headers {
+            // Set by reqwest from the URL
+            if name != "host" {
+                request = request.header(name, value);
+            }
+        }
+        request
+            .send()
+            .context(format!("S3 request {} {} failed", method, url))
+    }
+}
+
+/// Turns an error status into an error with the message from S3
+fn checked(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let body = response.text().unwrap_or_default();
+    Err(anyhow::format_err!(
+        "S3 request to {} failed with {}: {}",
+        url,
+        status,
+        body
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `get-vanilla` and `get-vanilla-query-order-key-case` of the AWS SigV4 test suite
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+    const AMZ_DATE: &str = "20150830T123600Z";
+    const SCOPE: &str = "20150830/us-east-1/service/aws4_request";
+    const EMPTY_PAYLOAD_HASH: &str =
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn sign(query: &[(&str, String)]) -> (String, String, String) {
+        let headers = BTreeMap::from([
+            ("host".to_string(), "example.amazonaws.com".to_string()),
+            ("x-amz-date".to_string(), AMZ_DATE.to_string()),
+        ]);
+        let (canonical_request, signed_headers) = canonical_request(
+            &Method::GET,
+            "/",
+            &canonical_query(query),
+            &headers,
+            EMPTY_PAYLOAD_HASH,
+        );
+        assert_eq!(signed_headers, "host;x-amz-date");
+        let string_to_sign = string_to_sign(AMZ_DATE, SCOPE, &canonical_request);
+        let signature = signature(SECRET_KEY, SCOPE, &string_to_sign).unwrap();
+        (canonical_request, string_to_sign, signature)
+    }
+
+    #[test]
+    fn get_vanilla() {
+        let (canonical_request, string_to_sign, signature) = sign(&[]);
+        assert_eq!(
+            canonical_request,
+            "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\n\
+             host;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            string_to_sign,
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             bb579772317eb040ac9ed261061d46c1f17a8133879d6129b6e1c25292927e63"
+        );
+        assert_eq!(
+            signature,
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn get_vanilla_query_order_key_case() {
+        let (canonical_request, _, signature) = sign(&[
+            ("Param2", "value2".to_string()),
+            ("Param1", "value1".to_string()),
+        ]);
+        assert!(canonical_request.starts_with("GET\n/\nParam1=value1&Param2=value2\n"));
+        assert_eq!(
+            signature,
+            "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn query_is_ordered_by_name() {
+        let query = canonical_query(&[
+            ("a", "1".to_string()),
+            ("a-b", "2".to_string()),
+            ("prefix", "in box/".to_string()),
+        ]);
+        assert_eq!(query, "a=1&a-b=2&prefix=in%20box%2F");
+    }
+
+    #[test]
+    fn listings_give_object_sizes() {
+        let result: ListBucketResult = quick_xml::de::from_str(
+            "<ListBucketResult>\
+             <IsTruncated>false</IsTruncated>\
+             <Contents>\
+             <Key>incoming/form.zip</Key>\
+             <LastModified>2024-01-02T03:04:05.000Z</LastModified>\
+             <Size>1234</Size>\
+             </Contents>\
+             </ListBucketResult>",
+        )
+        .unwrap();
+        assert_eq!(result.contents.len(), 1);
+        assert_eq!(result.contents[0].size, 1234);
+    }
+
+    #[test]
+    fn objects_over_the_maximum_size_are_refused() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let serving = std::thread::spawn(move || {
+            // Without and with a length, which reading must not rely on
+            for length in [None, Some(11), None] {
+                let request = server.recv().unwrap();
+                let body = std::io::Cursor::new(vec![b'x'; 11]);
+                let response = tiny_http::Response::new(200.into(), Vec::new(), body, length, None);
+                request.respond(response).unwrap();
+            }
+        });
+
+        let mut bucket = Bucket {
+            client: Client::new(),
+            name: "bucket".to_string(),
+            region: DEFAULT_REGION.to_string(),
+            endpoint: Some(Url::parse(&endpoint).unwrap()),
+            credentials: CredentialSource::Static(Credentials {
+                access_key: "AKIDEXAMPLE".to_string(),
+                secret_key: SECRET_KEY.to_string(),
+                session_token: None,
+            }),
+        };
+        assert!(bucket.get("form.zip", 10).is_err());
+        assert!(bucket.get("form.zip", 10).is_err());
+        assert_eq!(bucket.get("form.zip", 11).unwrap().unwrap().len(), 11);
+        serving.join().unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use openssl::rand::rand_bytes;
+use serde_derive::{Deserialize, Serialize};
+
+/// One point of the sharing polynomials, evaluated at `index` for every secret byte
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub index: u8,
+    pub value: Vec<u8>,
+}
+
+// Arithmetic in GF(2^8) with the AES reduction polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 == a^-1 for non-zero a
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+    result
+}
+
+/// Splits `secret` into `count` shares of which any `threshold` recover it
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, anyhow::Error> {
+    if threshold == 0 || threshold > count {
+        return Err(anyhow::format_err!(
+            "Invalid threshold: {} of {}",
+            threshold,
+            count
+        ));
+    }
+
+    let mut shares: Vec<Share> = (1..=count)
+        .map(|index| Share {
+            index,
+            value: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    let mut coefficients = vec![0u8; threshold as usize];
+    for byte in secret {
+        coefficients[0] = *byte;
+        rand_bytes(&mut coefficients[1..])?;
+        for share in shares.iter_mut() {
+            // Horner's rule
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, c| gf_mul(acc, share.index) ^ c);
+            share.value.push(y);
+        }
+    }
+    coefficients.fill(0);
+
+    Ok(shares)
+}
+
+/// Recovers the secret from at least `threshold` distinct shares
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>, anyhow::Error> {
+    let first = shares
+        .first()
+        .ok_or_else(|| anyhow::Error::msg("No shares given"))?;
+    for (i, share) in shares.iter().enumerate() {
+        if share.index == 0 {
+            return Err(anyhow::Error::msg("Invalid share index 0"));
+        }
+        if share.value.len() != first.value.len() {
+            return Err(anyhow::Error::msg("Shares have different lengths"));
+        }
+        if shares[..i].iter().any(|other| other.index == share.index) {
+            return Err(anyhow::format_err!("Duplicate share {}", share.index));
+        }
+    }
+
+    // Lagrange basis polynomials evaluated at x = 0
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            let (num, den) = shares
+                .iter()
+                .filter(|other| other.index != share.index)
+                .fold((1, 1), |(num, den), other| {
+                    (
+                        gf_mul(num, other.index),
+                        gf_mul(den, other.index ^ share.index),
+                    )
+                });
+            gf_mul(num, gf_inv(den))
+        })
+        .collect();
+
+    let secret = (0..first.value.len())
+        .map(|i| {
+            shares
+                .iter()
+                .zip(&basis)
+                .fold(0, |acc, (share, l)| acc ^ gf_mul(share.value[i], *l))
+        })
+        .collect();
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8; 32] = b"a content key of thirty-two byte";
+
+    #[test]
+    fn field_arithmetic_matches_aes() {
+        // FIPS 197, section 4.2
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        assert_eq!(gf_mul(0x57, 0x13), 0xfe);
+        assert_eq!(gf_mul(0x53, 0xca), 0x01);
+        assert_eq!(gf_inv(0x53), 0xca);
+        for a in 1..=255 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1, "inverse of {:#04x}", a);
+        }
+    }
+
+    #[test]
+    fn any_threshold_of_shares_recovers_the_secret() {
+        let shares = split(SECRET, 3, 5).unwrap();
+        for subset in 0u32..1 << shares.len() {
+            let chosen: Vec<Share> = shares
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| subset & 1 << i != 0)
+                .map(|(_, share)| share.clone())
+                .collect();
+            let combined = combine(&chosen);
+            match chosen.len() {
+                0 => assert!(combined.is_err()),
+                1 | 2 => assert_ne!(combined.unwrap(), SECRET, "subset {:#07b}", subset),
+                _ => assert_eq!(combined.unwrap(), SECRET, "subset {:#07b}", subset),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_shares_are_rejected() {
+        let shares = split(SECRET, 2, 3).unwrap();
+        let duplicate = [shares[0].clone(), shares[1].clone(), shares[0].clone()];
+        assert!(combine(&duplicate).is_err());
+        let zero = Share {
+            index: 0,
+            value: shares[0].value.clone(),
+        };
+        assert!(combine(&[zero, shares[1].clone()]).is_err());
+        let short = Share {
+            index: 3,
+            value: shares[2].value[1..].to_vec(),
+        };
+        assert!(combine(&[shares[0].clone(), short]).is_err());
+        assert!(split(SECRET, 0, 3).is_err());
+        assert!(split(SECRET, 4, 3).is_err());
+    }
+}
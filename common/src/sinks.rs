@@ -0,0 +1,94 @@
+use crate::bundle::Bundle;
+use crate::s3::{bucket_and_prefix, Bucket, BucketOptions};
+use anyhow::{Context, Error};
+use log::info;
+use std::path::{Component, Path, PathBuf};
+use url::Url;
+
+/// Where bundles go, one directory or prefix per target
+pub trait Sink {
+    /// `relative` is the path of the input below the source, its subdirectory
+    /// and file name
+    fn write(&mut self, bundle: &Bundle, target: &str, relative: &Path) -> Result<(), Error>;
+}
+
+/// Inputs end up as paths below the target, so they must stay below it
+fn check_relative(relative: &Path) -> Result<(), Error> {
+    let mut components = relative.components().peekable();
+    if components.peek().is_none()
+        || !components.all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow::format_err!("Invalid output path: {:?}", relative));
+    }
+    Ok(())
+}
+
+/// `<path>/<target>/<subdirectory>/<file name>`
+pub struct DirectorySink {
+    path: PathBuf,
+}
+
+impl Sink for DirectorySink {
+    fn write(&mut self, bundle: &Bundle, target: &str, relative: &Path) -> Result<(), Error> {
+        check_relative(relative)?;
+        bundle.write_to_path(&self.path, target, relative)
+    }
+}
+
+/// `<prefix><target>/<subdirectory>/<file name>` in a bucket, see
+/// `s3::BucketOptions` for the options
+pub struct S3Sink {
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl Sink for S3Sink {
+    fn write(&mut self, bundle: &Bundle, target: &str, relative: &Path) -> Result<(), Error> {
+        check_relative(relative)?;
+        let components = relative
+            .iter()
+            .map(|component| component.to_str())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow::format_err!("Invalid file name: {:?}", relative))?;
+        let key = format!("{}{}/{}", self.prefix, target, components.join("/"));
+        info!(".. output to: {}", key);
+        self.bucket.put(&key, bundle.to_bytes()?)
+    }
+}
+
+/// `file:///path` or a plain path for a local directory, or `s3://bucket/prefix`
+pub fn from_string(s: &str) -> Result<Box<dyn Sink>, Error> {
+    if !s.contains("://") {
+        return Ok(Box::new(DirectorySink {
+            path: PathBuf::from(s),
+        }));
+    }
+
+    let url = Url::parse(s).context(format!("Invalid output URL: {}", s))?;
+    let mut options = url.query_pairs();
+    match url.scheme() {
+        "file" => {
+            if let Some((key, _)) = options.next() {
+                return Err(anyhow::format_err!("Unknown output option: {}", key));
+            }
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow::format_err!("Invalid file URL: {}", s))?;
+            Ok(Box::new(DirectorySink { path }))
+        }
+        "s3" => {
+            let mut bucket_options = BucketOptions::default();
+            for (key, value) in options {
+                if !bucket_options.set(&key, &value)? {
+                    return Err(anyhow::format_err!("Unknown output option: {}", key));
+                }
+            }
+            let (name, prefix) = bucket_and_prefix(&url)?;
+            Ok(Box::new(S3Sink {
+                bucket: Bucket::new(&name, bucket_options)?,
+                prefix,
+            }))
+        }
+        scheme => Err(anyhow::format_err!("Unsupported output scheme: {}", scheme)),
+    }
+}
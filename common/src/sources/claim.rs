@@ -0,0 +1,297 @@
+//! Claiming files before reading them, so that several workers can share a queue.
+//!
+//! A worker claims `dir/name` by renaming it to `dir/processing/<worker>/name`.
+//! Only one rename can succeed. Claims are touched when taken and then every
+//! quarter of the timeout while they are held, and claims of other workers that
+//! are older than the timeout are taken over, as their worker has most likely
+//! crashed. A restarted worker picks up its own leftover claims right away.
+
+use crate::sources::Data;
+use anyhow::{Context, Error};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::ErrorKind::NotFound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const PROCESSING_DIR: &str = "processing";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// How often to look for stale claims
+pub const RECOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Source options `worker` and `claim_timeout`
+#[derive(Debug, Clone)]
+pub struct ClaimOptions {
+    /// The hostname by default. Several workers on one host have to be given
+    /// their own IDs, or they would take each other's claims as leftovers.
+    pub worker: String,
+    pub timeout: Duration,
+}
+
+impl Default for ClaimOptions {
+    fn default() -> Self {
+        Self {
+            worker: default_worker(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl ClaimOptions {
+    /// Takes the option if it is a claim option, returns whether it was
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, Error> {
+        match key {
+            "worker" => {
+                if value.is_empty() || value.contains('/') || value.starts_with('.') {
+                    return Err(anyhow::format_err!("Invalid worker ID: {}", value));
+                }
+                self.worker = value.to_string();
+            }
+            "claim_timeout" => self.timeout = Duration::from_secs(value.parse()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// The hostname, the same after a restart so that leftover claims are found
+fn default_worker() -> String {
+    hostname().replace('/', "_")
+}
+
+/// The name of this host, unescaped
+pub(crate) fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Keeps claims fresh while they are held, by touching them from a background
+/// thread every quarter of the claim timeout. The thread ends when this is dropped.
+pub struct Heartbeat {
+    held: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Heartbeat {
+    pub fn start<F>(timeout: Duration, touch: F) -> Self
+    where
+        F: Fn(&Path) -> Result<(), Error> + Send + 'static,
+    {
+        let held = Arc::new(Mutex::new(HashSet::<PathBuf>::new()));
+        let weak = Arc::downgrade(&held);
+        let interval = (timeout / 4).max(Duration::from_secs(1));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(held) = weak.upgrade() else {
+                return;
+            };
+            let paths: Vec<PathBuf> = held.lock().unwrap().iter().cloned().collect();
+            drop(held);
+            for path in paths {
+                if let Err(e) = touch(&path) {
+                    log::warn!("Failed to refresh claim on {}: {:#}", path.display(), e);
+                }
+            }
+        });
+        Self { held }
+    }
+
+    pub fn hold(&self, path: PathBuf) {
+        self.held.lock().unwrap().insert(path);
+    }
+
+    pub fn release(&self, path: &Path) {
+        self.held.lock().unwrap().remove(path);
+    }
+}
+
+/// Whether `relative` is inside the claims directory
+pub fn is_claimed(relative: &Path) -> bool {
+    relative.starts_with(PROCESSING_DIR)
+}
+
+/// Claims in a local directory
+pub struct LocalClaims {
+    root: PathBuf,
+    options: ClaimOptions,
+    last_recovery: Option<Instant>,
+    heartbeat: Heartbeat,
+}
+
+impl LocalClaims {
+    pub fn new(root: &Path, options: ClaimOptions) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            heartbeat: Heartbeat::start(options.timeout, touch),
+            options,
+            last_recovery: None,
+        }
+    }
+
+    fn processing(&self) -> PathBuf {
+        self.root.join(PROCESSING_DIR)
+    }
+
+    /// Where this worker keeps `relative` while processing it
+    pub fn claimed_path(&self, relative: &Path) -> PathBuf {
+        self.processing().join(&self.options.worker).join(relative)
+    }
+
+    /// Reads a file this worker has claimed
+    pub fn read(&self, relative: PathBuf) -> Result<Data, Error> {
+        let contents = fs::read(self.claimed_path(&relative)).context("Failed to read file")?;
+        Data::local(relative, contents)
+    }
+
+    /// Moves `relative` into this worker's claims. `None` if it is already gone,
+    /// processed or claimed by another worker.
+    pub fn claim(&self, relative: &Path) -> Result<Option<PathBuf>, Error> {
+        self.take(&self.root.join(relative), relative)
+    }
+
+    fn take(&self, from: &Path, relative: &Path) -> Result<Option<PathBuf>, Error> {
+        let claimed = self.claimed_path(relative);
+        if let Some(parent) = claimed.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+        match fs::rename(from, &claimed) {
+            Ok(()) => (),
+            Err(e) if e.kind() == NotFound => return Ok(None),
+            Err(e) => return Err(Error::new(e).context(format!("Failed to claim {:?}", from))),
+        }
+        self.hold(&claimed)?;
+        Ok(Some(claimed))
+    }
+
+    /// Touches a claim and keeps it fresh until it is released
+    fn hold(&self, claimed: &Path) -> Result<(), Error> {
+        touch(claimed)?;
+        self.heartbeat.hold(claimed.to_path_buf());
+        Ok(())
+    }
+
+    /// Takes over leftover claims of this worker and stale claims of others.
+    /// Returns their relative paths, now claimed by this worker. Does nothing if
+    /// called again within `RECOVERY_INTERVAL`.
+    pub fn recover(&mut self) -> Result<Vec<PathBuf>, Error> {
+        if self
+            .last_recovery
+            .is_some_and(|last| last.elapsed() < RECOVERY_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_recovery = Some(Instant::now());
+
+        let workers = match fs::read_dir(self.processing()) {
+            Ok(workers) => workers,
+            Err(e) if e.kind() == NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut recovered = Vec::new();
+        for worker in workers {
+            let worker = worker?;
+            if !worker.file_type()?.is_dir() {
+                continue;
+            }
+            let own = worker.file_name() == self.options.worker.as_str();
+            for (path, metadata) in claimed_files(&worker.path())? {
+                let age = metadata.modified()?.elapsed().unwrap_or_default();
+                if !own && age < self.options.timeout {
+                    continue;
+                }
+                let relative = path.strip_prefix(worker.path())?.to_path_buf();
+                let taken = if own {
+                    self.hold(&path)?;
+                    true
+                } else {
+                    self.take(&path, &relative)?.is_some()
+                };
+                if taken {
+                    log::warn!(
+                        "Recovered claim on {} from {:?}",
+                        relative.display(),
+                        worker.file_name()
+                    );
+                    recovered.push(relative);
+                }
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Removes a processed file
+    pub fn release(&self, relative: &Path) -> Result<(), Error> {
+        let claimed = self.claimed_path(relative);
+        self.heartbeat.release(&claimed);
+        fs::remove_file(&claimed).context("Failed to remove file")
+    }
+}
+
+/// Sets the modification time of a claimed file to now
+fn touch(claimed: &Path) -> Result<(), Error> {
+    File::options()
+        .write(true)
+        .open(claimed)
+        .context(format!("Failed to open {}", claimed.display()))?
+        .set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+/// Files under a worker's claims directory, recursing into subdirectories
+fn claimed_files(path: &Path) -> Result<Vec<(PathBuf, fs::Metadata)>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // Released by its worker since listing the directory
+            Err(e) if e.kind() == NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_dir() {
+            files.extend(claimed_files(&entry.path())?);
+        } else if metadata.is_file() {
+            files.push((entry.path(), metadata));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_are_kept_fresh_and_found_after_a_restart() {
+        let root = std::env::temp_dir().join(format!("claim-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("form.zip"), "form").unwrap();
+        let options = ClaimOptions {
+            worker: "worker".to_string(),
+            timeout: Duration::from_secs(4),
+        };
+
+        let claims = LocalClaims::new(&root, options.clone());
+        let claimed = claims.claim(Path::new("form.zip")).unwrap().unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&claimed)
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        let modified = fs::metadata(&claimed).unwrap().modified().unwrap();
+        assert!(modified.elapsed().unwrap() < Duration::from_secs(2));
+        drop(claims);
+
+        let mut restarted = LocalClaims::new(&root, options);
+        assert_eq!(restarted.recover().unwrap(), [PathBuf::from("form.zip")]);
+        restarted.release(Path::new("form.zip")).unwrap();
+        assert!(!claimed.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,36 +1,181 @@
+use crate::sources::claim::{
+    is_claimed, ClaimOptions, LocalClaims, PROCESSING_DIR, RECOVERY_INTERVAL,
+};
 use crate::sources::{Data, Source};
+use crate::watch::{is_ignored, Readiness};
 use anyhow::Context;
 use anyhow::Error;
 use log::info;
-use notify::event::AccessKind;
-use notify::{EventKind, Watcher};
+use notify::{RecommendedWatcher, Watcher};
+use std::collections::VecDeque;
 use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::io::ErrorKind::NotFound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a file must be left alone before `next_available` takes it, as no
+/// events tell whether it is still being written
+pub const DEFAULT_MIN_AGE: Duration = Duration::from_secs(10);
 
 pub struct FileSource {
     path: PathBuf,
+    recursive: bool,
+    min_age: Duration,
     rx: Receiver<PathBuf>,
     thread: Option<JoinHandle<Result<(), Box<Error>>>>,
+    claims: LocalClaims,
+    recovered: VecDeque<PathBuf>,
+}
+
+/// Regular files in the directory, and in its subdirectories if `recursive`.
+/// Ignored files and directories and claimed files are skipped.
+pub(super) fn regular_files(
+    path: &Path,
+    recursive: bool,
+) -> Result<Vec<(PathBuf, fs::Metadata)>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // Removed since listing the directory
+            Err(e) if e.kind() == NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let path = entry.path();
+        if is_ignored(&path) || entry.file_name() == PROCESSING_DIR {
+            continue;
+        }
+        if metadata.is_file() {
+            files.push((path, metadata));
+        } else if recursive && metadata.is_dir() {
+            files.extend(regular_files(&path, recursive)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Regular files in the directory that have not been modified for `min_age`,
+/// oldest first. Newer ones may still be written to.
+pub fn settled_files(
+    path: &Path,
+    recursive: bool,
+    min_age: Duration,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for (path, metadata) in regular_files(path, recursive)? {
+        let modified = metadata.modified()?;
+        if modified.elapsed().unwrap_or_default() >= min_age {
+            files.push((modified, path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
 }
 
-fn watcher_thread(path: PathBuf, file_tx: Sender<PathBuf>) -> Result<(), Box<anyhow::Error>> {
-    info!("Starting watcher");
-    let (event_tx, event_rx) = channel();
-    let mut watcher = notify::recommended_watcher(event_tx).expect("Failed to create watcher");
-    watcher
-        .watch(path.as_ref(), notify::RecursiveMode::NonRecursive)
-        .expect("Failed to watch directory");
+/// Claims a recovered file or the oldest settled one in the directory, without
+/// waiting. Returns its relative path, `None` if there is none.
+fn claim_settled(
+    path: &Path,
+    recursive: bool,
+    min_age: Duration,
+    claims: &mut LocalClaims,
+    recovered: &mut VecDeque<PathBuf>,
+) -> Result<Option<PathBuf>, Error> {
+    recovered.extend(claims.recover()?);
+    if let Some(relative) = recovered.pop_front() {
+        return Ok(Some(relative));
+    }
+    let files = settled_files(path, recursive, min_age)
+        .context(format!("Failed to scan {}", path.display()))?;
+    for fname in files {
+        let relative = fname.strip_prefix(path)?.to_path_buf();
+        if claims.claim(&relative)?.is_some() {
+            return Ok(Some(relative));
+        }
+    }
+    Ok(None)
+}
+
+/// Queues the settled files of `unsettled`, returns those still modified less
+/// than `min_age` ago. Those that are gone have been handled on their events.
+fn queue_settled(
+    unsettled: Vec<PathBuf>,
+    min_age: Duration,
+    file_tx: &Sender<PathBuf>,
+) -> Result<Vec<PathBuf>, SendError<PathBuf>> {
+    let mut young = Vec::new();
+    for path in unsettled {
+        let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if modified.elapsed().unwrap_or_default() >= min_age {
+            file_tx.send(path)?;
+        } else {
+            young.push(path);
+        }
+    }
+    Ok(young)
+}
+
+/// Queues the existing files and then those the events say are ready. Takes
+/// the watcher along to keep it running.
+fn watcher_thread(
+    path: PathBuf,
+    readiness: Readiness,
+    recursive: bool,
+    min_age: Duration,
+    _watcher: RecommendedWatcher,
+    event_rx: Receiver<notify::Result<notify::Event>>,
+    file_tx: Sender<PathBuf>,
+) -> Result<(), Box<anyhow::Error>> {
+    // Scanned only after the watch is in place, so that nothing written in between
+    // is missed. A file can then be both scanned and evented, see `FileSource::next`.
+    let existing = regular_files(&path, recursive)
+        .context(format!("Failed to scan {}", path.display()))
+        .map_err(Box::new)?;
+    info!("Queueing {} existing files", existing.len());
+    let mut existing: Vec<_> = existing
+        .into_iter()
+        .map(|(path, metadata)| (metadata.modified().ok(), path))
+        .collect();
+    existing.sort();
+    let existing = existing.into_iter().map(|(_, path)| path).collect();
+    // Files that may still be written are queued once they settle, or on their
+    // events when they are written to again
+    let Ok(mut unsettled) = queue_settled(existing, min_age, &file_tx) else {
+        // The source is gone
+        return Ok(());
+    };
+    let mut recheck = Instant::now() + min_age;
 
     info!("Watching for events...");
-    for event in event_rx {
-        let event = event.expect("Failed to get event");
-        if let EventKind::Access(AccessKind::Close(_)) = &event.kind {
-            for path in event.paths {
-                file_tx.send(path).expect("Failed to send filename");
+    loop {
+        if !unsettled.is_empty() && Instant::now() >= recheck {
+            let Ok(young) = queue_settled(unsettled, min_age, &file_tx) else {
+                return Ok(());
+            };
+            unsettled = young;
+            recheck = Instant::now() + min_age;
+        }
+        let event = if unsettled.is_empty() {
+            event_rx.recv().map_err(RecvTimeoutError::from)
+        } else {
+            event_rx.recv_timeout(recheck.saturating_duration_since(Instant::now()))
+        };
+        let event = match event {
+            Ok(event) => event.context("Failed to get event").map_err(Box::new)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        for ready in readiness.ready_paths(event) {
+            if !ready.strip_prefix(&path).is_ok_and(is_claimed) && file_tx.send(ready).is_err() {
+                return Ok(());
             }
         }
     }
@@ -38,12 +183,49 @@ fn watcher_thread(path: PathBuf, file_tx: Sender<PathBuf>) -> Result<(), Box<any
 }
 
 impl FileSource {
-    pub fn new(path: &str) -> Self {
-        let path = PathBuf::from(path);
+    pub fn new(
+        path: PathBuf,
+        readiness: Readiness,
+        recursive: bool,
+        min_age: Duration,
+        claim: ClaimOptions,
+    ) -> Result<Self, Error> {
+        info!("Starting watcher");
+        let (event_tx, event_rx) = channel();
+        let mut watcher =
+            notify::recommended_watcher(event_tx).context("Failed to create watcher")?;
+        let mode = if recursive {
+            notify::RecursiveMode::Recursive
+        } else {
+            notify::RecursiveMode::NonRecursive
+        };
+        watcher
+            .watch(&path, mode)
+            .context(format!("Failed to watch {}", path.display()))?;
+
         let (tx, rx) = channel();
         let thread_path = path.clone();
-        let thread = Some(thread::spawn(move || watcher_thread(thread_path, tx)));
-        Self { path, thread, rx }
+        let thread = Some(thread::spawn(move || {
+            watcher_thread(
+                thread_path,
+                readiness,
+                recursive,
+                min_age,
+                watcher,
+                event_rx,
+                tx,
+            )
+        }));
+        let claims = LocalClaims::new(&path, claim);
+        Ok(Self {
+            path,
+            recursive,
+            min_age,
+            thread,
+            rx,
+            claims,
+            recovered: VecDeque::new(),
+        })
     }
 }
 
@@ -67,24 +249,49 @@ impl Source for FileSource {
             });
         }
 
-        // Read a filename from queue
-        let fname = self.rx.recv().context("Failed to receive filename")?;
-        info!("New file available: {}", fname.to_string_lossy());
-        let contents = fs::read(&fname).context("Failed to read file")?;
+        loop {
+            if let Some(relative) = self.recovered.pop_front() {
+                return self.claims.read(relative);
+            }
+            self.recovered.extend(self.claims.recover()?);
+            if !self.recovered.is_empty() {
+                continue;
+            }
 
-        let data = Data {
-            contents,
-            id: fname
-                .file_name()
-                .ok_or_else(|| anyhow::Error::msg("Unable to get input filename"))?
-                .to_os_string(),
-        };
-        Ok(data)
+            // Read a filename from queue. A file that is gone has already been
+            // handled under an earlier, duplicate entry, or by another worker.
+            let fname = match self.rx.recv_timeout(RECOVERY_INTERVAL) {
+                Ok(fname) => fname,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(e) => return Err(Error::new(e).context("Failed to receive filename")),
+            };
+            info!("New file available: {}", fname.to_string_lossy());
+            let relative = fname.strip_prefix(&self.path)?.to_path_buf();
+            match self.claims.claim(&relative)? {
+                Some(_) => return self.claims.read(relative),
+                None => info!(".. already processed or claimed, skipping"),
+            }
+        }
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        info!("Next available file requested");
+        // Events lag behind, a scan sees every file written so far. Files still
+        // being written are left for the next run.
+        match claim_settled(
+            &self.path,
+            self.recursive,
+            self.min_age,
+            &mut self.claims,
+            &mut self.recovered,
+        )? {
+            Some(relative) => self.claims.read(relative).map(Some),
+            None => Ok(None),
+        }
     }
 
-    fn confirm(&self, id: OsString) -> Result<(), Error> {
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
         info!("Removing file: {}", id.to_string_lossy());
-        let fname = self.path.join(id);
-        fs::remove_file(fname).context("Failed to remove file")
+        self.claims.release(Path::new(&id))
     }
 }
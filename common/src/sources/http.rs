@@ -0,0 +1,390 @@
+//! Uploads POSTed to a built-in listener, kept in memory only. An upload is
+//! answered once it is confirmed, so a client that gets `200 OK` knows that it
+//! was encrypted. Uploads that fail are answered with `500` when the source is
+//! dropped.
+//!
+//! A few uploads are read at a time and a few more wait for `next`, so at most
+//! `(READERS + QUEUE_LENGTH) * max_size` bytes are held besides the handed out
+//! ones. Uploads beyond that are refused with `503`, for the client to retry.
+//!
+//! The listener does no authentication: bind it to localhost or a Unix socket
+//! that only the web frontend can reach.
+
+use crate::sources::{Data, Options, Source};
+use anyhow::{Context, Error};
+use log::{info, warn};
+use openssl::rand::rand_bytes;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Method, Request, Response, Server};
+use url::Url;
+
+const DEFAULT_MAX_SIZE: u64 = 50_000_000;
+/// Uploads read at the same time, slow clients hold up one reader each
+const READERS: usize = 4;
+/// Uploads read but not yet handed out by `next`
+const QUEUE_LENGTH: usize = 4;
+
+/// Why an upload was not accepted, as an HTTP status and a message for the client
+type Refusal = (u16, String);
+
+/// Settings given as query parameters of the source URL, for example
+/// `http://127.0.0.1:8080/upload?max_size=10000000`
+#[derive(Debug)]
+pub struct HttpOptions {
+    /// Largest accepted request body in bytes
+    max_size: u64,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+impl HttpOptions {
+    pub fn from_options(options: Options) -> Result<Self, Error> {
+        let mut http_options = Self::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "max_size" => http_options.max_size = value.parse()?,
+                _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+            }
+        }
+        Ok(http_options)
+    }
+}
+
+/// Listens on `http://host:port/path` or `http+unix:///path/to/socket`.
+///
+/// The body of a POST is the upload, or the first file of a
+/// `multipart/form-data` body. POSTs below the path go to that subdirectory,
+/// for example `/upload/contact` to `contact`.
+pub struct HttpSource {
+    uploads: Receiver<(Data, Request)>,
+    /// Requests of uploads handed out by `next`, answered by `confirm`
+    pending: HashMap<OsString, Request>,
+}
+
+impl HttpSource {
+    pub fn new(url: &Url, options: HttpOptions) -> Result<Self, Error> {
+        let listen_error = |e| anyhow::format_err!("Failed to listen on {}: {}", url, e);
+        let (server, base) = if url.scheme() == "http+unix" {
+            let path = PathBuf::from(percent_decode_str(url.path()).decode_utf8()?.as_ref());
+            // Left behind by a previous run, binding would fail
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+            }
+            (
+                Server::http_unix(&path).map_err(listen_error)?,
+                String::new(),
+            )
+        } else {
+            let addresses = url.socket_addrs(|| Some(80))?;
+            let base = url.path().trim_end_matches('/').to_string();
+            (Server::http(&addresses[..]).map_err(listen_error)?, base)
+        };
+        info!("Listening for uploads on {}", url);
+
+        let (tx, rx) = sync_channel(QUEUE_LENGTH);
+        let server = Arc::new(server);
+        for _ in 0..READERS {
+            let server = Arc::clone(&server);
+            let base = base.clone();
+            let tx = tx.clone();
+            let max_size = options.max_size;
+            thread::spawn(move || listen(&server, &base, max_size, &tx));
+        }
+        Ok(Self {
+            uploads: rx,
+            pending: HashMap::new(),
+        })
+    }
+}
+
+/// One of the readers, taking requests until the server stops
+fn listen(server: &Server, base: &str, max_size: u64, tx: &SyncSender<(Data, Request)>) {
+    loop {
+        match server.recv() {
+            Ok(request) => receive(request, base, max_size, tx),
+            Err(e) => {
+                warn!("Upload listener stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn receive(mut request: Request, base: &str, max_size: u64, tx: &SyncSender<(Data, Request)>) {
+    let data = match read_upload(&mut request, base, max_size) {
+        Ok(data) => data,
+        Err(refusal) => return refuse(request, refusal),
+    };
+    let id = data.id.to_string_lossy().into_owned();
+    match tx.try_send((data, request)) {
+        Ok(()) => info!("Received upload {}", id),
+        Err(TrySendError::Full((_, request))) => refuse(
+            request,
+            (503, "Too many uploads waiting, try again later".to_string()),
+        ),
+        // The source is gone, dropping the request answers it with 500
+        Err(TrySendError::Disconnected(_)) => (),
+    }
+}
+
+fn refuse(request: Request, (status, message): Refusal) {
+    warn!("Refused upload to {}: {}", request.url(), message);
+    let response = Response::from_string(message).with_status_code(status);
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to respond: {}", e);
+    }
+}
+
+fn read_upload(request: &mut Request, base: &str, max_size: u64) -> Result<Data, Refusal> {
+    if *request.method() != Method::Post {
+        return Err((405, "Only POST is accepted".to_string()));
+    }
+    let subdirectory = subdirectory(request.url(), base).ok_or((404, "Not found".to_string()))?;
+    let too_large = || (413, format!("Uploads are limited to {} bytes", max_size));
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > max_size)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_size + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| (400, format!("Failed to read upload: {}", e)))?;
+    if body.len() as u64 > max_size {
+        return Err(too_large());
+    }
+
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .map(|header| header.value.as_str().to_string());
+    let (filename, contents) = match form_boundary(content_type.as_deref().unwrap_or(""))? {
+        Some(boundary) => form_file(&body, &boundary)?,
+        None => (None, body),
+    };
+
+    let internal = |e: Error| (500, e.to_string());
+    let name = unique_name(filename.as_deref()).map_err(internal)?;
+    Data::local(subdirectory.join(name), contents).map_err(internal)
+}
+
+/// The directory below `base` that the upload was POSTed to, `None` outside it
+fn subdirectory(target: &str, base: &str) -> Option<PathBuf> {
+    let path = target.split('?').next()?;
+    let rest = path.strip_prefix(base)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let mut subdirectory = PathBuf::new();
+    for segment in rest.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if segment == "." || segment == ".." || segment.contains(['/', '\0']) {
+            return None;
+        }
+        subdirectory.push(segment.as_ref());
+    }
+    Some(subdirectory)
+}
+
+/// The boundary if `content_type` is `multipart/form-data`, `None` for raw uploads
+fn form_boundary(content_type: &str) -> Result<Option<String>, Refusal> {
+    let (mime, parameters) = content_type.split_once(';').unwrap_or((content_type, ""));
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return Ok(None);
+    }
+    parameters
+        .split(';')
+        .find_map(|parameter| {
+            let (key, value) = parameter.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("boundary")
+                .then(|| value.trim_matches('"').to_string())
+        })
+        .map(Some)
+        .ok_or((400, "No boundary in multipart/form-data".to_string()))
+}
+
+/// File name and contents of the first file in a form, see RFC 7578
+fn form_file(body: &[u8], boundary: &str) -> Result<(Option<String>, Vec<u8>), Refusal> {
+    let invalid = |message: &str| (400, format!("Invalid form: {}", message));
+    let delimiter = format!("\r\n--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+    // The first delimiter starts the body or follows a preamble
+    let mut rest = match body.strip_prefix(&delimiter[2..]) {
+        Some(rest) => rest,
+        None => {
+            let start = find(body, delimiter).ok_or_else(|| invalid("no boundary"))?;
+            &body[start + delimiter.len()..]
+        }
+    };
+    // `--` after a delimiter closes the form
+    while !rest.starts_with(b"--") {
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| invalid("no line break after boundary"))?;
+        let end = find(rest, delimiter).ok_or_else(|| invalid("unterminated part"))?;
+        let part = &rest[..end];
+        rest = &rest[end + delimiter.len()..];
+
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let (length, headers) = match httparse::parse_headers(part, &mut headers) {
+            Ok(httparse::Status::Complete(parsed)) => parsed,
+            _ => return Err(invalid("bad part headers")),
+        };
+        let filename = headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("Content-Disposition"))
+            .and_then(|header| disposition_filename(header.value));
+        if filename.is_some() {
+            return Ok((filename, part[length..].to_vec()));
+        }
+    }
+    Err((400, "No file in the form".to_string()))
+}
+
+/// The `filename` parameter of a `Content-Disposition` header, set for files
+fn disposition_filename(value: &[u8]) -> Option<String> {
+    let value = String::from_utf8_lossy(value);
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (parameter, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut parameter = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (i, '"') => break i,
+                        (_, '\\') => parameter.push(chars.next()?.1),
+                        (_, c) => parameter.push(c),
+                    }
+                };
+                (parameter, &quoted[end + 1..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim_end().to_string(), &after[end..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case("filename") {
+            return Some(parameter);
+        }
+        rest = next.split_once(';')?.1;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A random name, followed by the client's file name if it gave one. Client
+/// names can neither collide nor point outside the output directory this way.
+fn unique_name(filename: Option<&str>) -> Result<String, Error> {
+    let mut random = [0; 8];
+    rand_bytes(&mut random)?;
+    let prefix = hex::encode(random);
+    let name = filename
+        .and_then(|filename| Path::new(filename).file_name())
+        .and_then(|name| name.to_str());
+    Ok(match name {
+        Some(name) => format!("{}-{}", prefix, name),
+        None => prefix,
+    })
+}
+
+impl Source for HttpSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        info!("Next upload requested");
+        let (data, request) = self.uploads.recv().context("Upload listener stopped")?;
+        self.pending.insert(data.id.clone(), request);
+        Ok(data)
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        match self.uploads.try_recv() {
+            Ok((data, request)) => {
+                self.pending.insert(data.id.clone(), request);
+                Ok(Some(data))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(e) => Err(Error::new(e).context("Upload listener stopped")),
+        }
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        let request = self
+            .pending
+            .remove(&id)
+            .ok_or_else(|| anyhow::format_err!("Unknown upload: {:?}", id))?;
+        info!("Answering upload {}", id.to_string_lossy());
+        // The upload is encrypted already, a client that went away is not our failure
+        if let Err(e) = request.respond(Response::from_string("OK")) {
+            warn!(
+                "Failed to respond to upload {}: {}",
+                id.to_string_lossy(),
+                e
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_file_of_a_form_is_the_upload() {
+        let body = b"preamble\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"site\"\r\n\
+            \r\n\
+            contact\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\"; c.zip\"\r\n\
+            Content-Type: application/zip\r\n\
+            \r\n\
+            PK\r\n--X\r\n\
+            --XyZ--\r\n";
+        assert_eq!(
+            form_file(body, "XyZ"),
+            Ok((Some("a \"b\"; c.zip".to_string()), b"PK\r\n--X".to_vec()))
+        );
+    }
+
+    #[test]
+    fn forms_without_files_are_refused() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"site\"\r\n\
+            \r\n\
+            contact\r\n\
+            --XyZ--\r\n";
+        assert_eq!(form_file(body, "XyZ").unwrap_err().0, 400);
+        assert_eq!(form_file(b"--XyZ\r\n\r\nno end", "XyZ").unwrap_err().0, 400);
+        assert_eq!(
+            disposition_filename(b"form-data; filename=plain.zip; name=file").as_deref(),
+            Some("plain.zip")
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! Bundles mailed as attachments, collected from a folder over IMAPS.
+//!
+//! Messages are fetched without marking them seen, so a message is only taken
+//! out of the folder once all of its bundles have been confirmed. Messages
+//! without bundle attachments and those over `MAX_MESSAGE_SIZE` are marked
+//! seen and left alone.
+
+use crate::sources::mail::{self, MAX_MESSAGE_SIZE};
+use crate::sources::{poll_next, Data, Options, Source, SESSION_TIMEOUT};
+use anyhow::{Context, Error};
+use imap_proto::types::{AttributeValue, Capability, MailboxDatum, Response, Status};
+use log::{info, warn};
+use mail_parser::MessageParser;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use percent_encoding::percent_decode_str;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_PORT: u16 = 993;
+const DEFAULT_FOLDER: &str = "INBOX";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// A message of `MAX_MESSAGE_SIZE` and the response around it
+const MAX_RESPONSE_SIZE: usize = MAX_MESSAGE_SIZE + 64 * 1024;
+
+/// Settings given as query parameters of the source URL, for example
+/// `imaps://bundles@mail.example.com/Bundles?password_file=/etc/queue/imap&done=Processed`
+#[derive(Debug, Default)]
+pub struct ImapOptions {
+    /// File holding the password of the mailbox
+    password_file: Option<PathBuf>,
+    /// Folder to move processed messages to instead of deleting them
+    done: Option<String>,
+    /// How long to wait before searching an empty folder again
+    poll: Option<Duration>,
+    /// CA certificates to trust instead of the system ones, for test servers
+    ca_file: Option<PathBuf>,
+}
+
+impl ImapOptions {
+    pub fn from_options(options: Options) -> Result<Self, Error> {
+        let mut imap_options = Self::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "password_file" => imap_options.password_file = Some(PathBuf::from(value)),
+                "done" => imap_options.done = Some(value),
+                "poll" => imap_options.poll = Some(Duration::from_secs(value.parse()?)),
+                "ca_file" => imap_options.ca_file = Some(PathBuf::from(value)),
+                _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+            }
+        }
+        Ok(imap_options)
+    }
+}
+
+/// `"..."` with escapes, for mailbox names and credentials in commands
+fn quote(s: &str) -> Result<String, Error> {
+    if s.contains(['\r', '\n', '\0']) {
+        return Err(anyhow::format_err!("Can not quote {:?} for IMAP", s));
+    }
+    Ok(format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// A logged in connection with a folder selected
+struct Session {
+    stream: SslStream<TcpStream>,
+    /// Received but not yet parsed
+    buffer: Vec<u8>,
+    next_tag: u32,
+    capabilities: Vec<String>,
+}
+
+impl Session {
+    fn read_response(&mut self) -> Result<Response<'static>, Error> {
+        loop {
+            match imap_proto::parser::parse_response(&self.buffer) {
+                Ok((rest, response)) => {
+                    let consumed = self.buffer.len() - rest.len();
+                    let response = response.into_owned();
+                    self.buffer.drain(..consumed);
+                    return Ok(response);
+                }
+                Err(e) if e.is_incomplete() => {
+                    if self.buffer.len() > MAX_RESPONSE_SIZE {
+                        return Err(anyhow::format_err!(
+                            "IMAP response exceeds {} bytes",
+                            MAX_RESPONSE_SIZE
+                        ));
+                    }
+                    let mut chunk = [0; 16 * 1024];
+                    let read = self.stream.read(&mut chunk)?;
+                    if read == 0 {
+                        return Err(Error::msg("IMAP server closed the connection"));
+                    }
+                    self.buffer.extend_from_slice(&chunk[..read]);
+                }
+                Err(e) => return Err(anyhow::format_err!("Invalid IMAP response: {:?}", e)),
+            }
+        }
+    }
+
+    /// Sends a command and returns the untagged responses to it
+    fn run(&mut self, command: &str) -> Result<Vec<Response<'static>>, Error> {
+        let tag = format!("A{}", self.next_tag);
+        self.next_tag += 1;
+        self.stream
+            .write_all(format!("{} {}\r\n", tag, command).as_bytes())?;
+        let mut responses = Vec::new();
+        loop {
+            match self.read_response()? {
+                Response::Done {
+                    tag: done,
+                    status,
+                    information,
+                    ..
+                } if done.0 == tag => {
+                    if status != Status::Ok {
+                        // Not the command itself, it may contain the password
+                        let verb = command.split(' ').next().unwrap_or_default();
+                        return Err(anyhow::format_err!(
+                            "IMAP {} failed: {}",
+                            verb,
+                            information.unwrap_or_default()
+                        ));
+                    }
+                    return Ok(responses);
+                }
+                Response::Data {
+                    status: Status::Bye,
+                    information,
+                    ..
+                } => {
+                    return Err(anyhow::format_err!(
+                        "IMAP server closed the connection: {}",
+                        information.unwrap_or_default()
+                    ))
+                }
+                response => responses.push(response),
+            }
+        }
+    }
+
+    fn has_capability(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|capability| capability.eq_ignore_ascii_case(name))
+    }
+
+    /// UIDs of the unseen messages, oldest first
+    fn search_unseen(&mut self) -> Result<Vec<u32>, Error> {
+        let mut uids: Vec<u32> = self
+            .run("UID SEARCH UNSEEN")?
+            .into_iter()
+            .filter_map(|response| match response {
+                Response::MailboxData(MailboxDatum::Search(uids)) => Some(uids),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        uids.sort_unstable();
+        Ok(uids)
+    }
+
+    /// The attributes of a message, `None` if it is gone
+    fn fetch_attributes(
+        &mut self,
+        uid: u32,
+        items: &str,
+    ) -> Result<Option<Vec<AttributeValue<'static>>>, Error> {
+        let responses = self.run(&format!("UID FETCH {} {}", uid, items))?;
+        for response in responses {
+            let Response::Fetch(_, attributes) = response else {
+                continue;
+            };
+            // Servers may send unsolicited FETCH responses about other messages
+            if attributes.contains(&AttributeValue::Uid(uid)) {
+                return Ok(Some(attributes));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The size of a message in bytes, `None` if it is gone
+    fn size(&mut self, uid: u32) -> Result<Option<usize>, Error> {
+        let attributes = self.fetch_attributes(uid, "RFC822.SIZE")?;
+        Ok(attributes
+            .into_iter()
+            .flatten()
+            .find_map(|attribute| match attribute {
+                AttributeValue::Rfc822Size(size) => Some(size as usize),
+                _ => None,
+            }))
+    }
+
+    /// The whole message, `None` if it is gone
+    fn fetch(&mut self, uid: u32) -> Result<Option<Vec<u8>>, Error> {
+        let attributes = self.fetch_attributes(uid, "BODY.PEEK[]")?;
+        Ok(attributes
+            .into_iter()
+            .flatten()
+            .find_map(|attribute| match attribute {
+                AttributeValue::BodySection {
+                    data: Some(data), ..
+                } => Some(data.into_owned()),
+                _ => None,
+            }))
+    }
+
+    fn mark_seen(&mut self, uid: u32) -> Result<(), Error> {
+        self.run(&format!("UID STORE {} +FLAGS (\\Seen)", uid))?;
+        Ok(())
+    }
+
+    /// Moves the message to `folder`, or deletes it
+    fn remove(&mut self, uid: u32, folder: Option<&str>) -> Result<(), Error> {
+        if let Some(folder) = folder {
+            if self.has_capability("MOVE") {
+                self.run(&format!("UID MOVE {} {}", uid, quote(folder)?))?;
+                return Ok(());
+            }
+            self.run(&format!("UID COPY {} {}", uid, quote(folder)?))?;
+        }
+        self.run(&format!("UID STORE {} +FLAGS (\\Seen \\Deleted)", uid))?;
+        // Without UIDPLUS only EXPUNGE is left, which would also remove messages
+        // others have marked deleted. Those are left for the server to clean up.
+        if self.has_capability("UIDPLUS") {
+            self.run(&format!("UID EXPUNGE {}", uid))?;
+        }
+        Ok(())
+    }
+}
+
+/// An attachment waiting to be handed out
+struct Attachment {
+    uid: u32,
+    id: String,
+    contents: Vec<u8>,
+}
+
+/// Attachments of the unseen messages in a folder, `imaps://user@host[:port]/folder`
+pub struct ImapSource {
+    hostname: String,
+    port: u16,
+    username: String,
+    password: String,
+    folder: String,
+    options: ImapOptions,
+    session: Option<Session>,
+    attachments: VecDeque<Attachment>,
+    /// UIDs of handed out attachments
+    handed_out: HashMap<OsString, u32>,
+    /// Attachments not yet confirmed per message
+    unconfirmed: HashMap<u32, usize>,
+}
+
+impl ImapSource {
+    pub fn new(url: &Url, options: ImapOptions) -> Result<Self, Error> {
+        let hostname = url
+            .host_str()
+            .ok_or_else(|| anyhow::format_err!("No host in {}", url))?
+            .to_string();
+        let username = percent_decode_str(url.username())
+            .decode_utf8()?
+            .into_owned();
+        if username.is_empty() {
+            return Err(anyhow::format_err!("No username in {}", url));
+        }
+        let password_file = options
+            .password_file
+            .as_ref()
+            .ok_or_else(|| Error::msg("IMAP sources need a password_file"))?;
+        let password = fs::read_to_string(password_file)
+            .context(format!("Error reading password: {:?}", password_file))?
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        let folder = percent_decode_str(url.path()).decode_utf8()?;
+        let folder = match folder.trim_matches('/') {
+            "" => DEFAULT_FOLDER.to_string(),
+            folder => folder.to_string(),
+        };
+
+        let mut source = Self {
+            hostname,
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            username,
+            password,
+            folder,
+            options,
+            session: None,
+            attachments: VecDeque::new(),
+            handed_out: HashMap::new(),
+            unconfirmed: HashMap::new(),
+        };
+        source.session = Some(source.connect()?);
+        Ok(source)
+    }
+
+    fn connect(&self) -> Result<Session, Error> {
+        info!("Connecting to IMAP server {}:{}", self.hostname, self.port);
+        let tcp = TcpStream::connect((self.hostname.as_str(), self.port)).context(format!(
+            "Error connecting to {}:{}",
+            self.hostname, self.port
+        ))?;
+        tcp.set_read_timeout(Some(SESSION_TIMEOUT))?;
+        tcp.set_write_timeout(Some(SESSION_TIMEOUT))?;
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        if let Some(ca_file) = &self.options.ca_file {
+            connector
+                .set_ca_file(ca_file)
+                .context(format!("Error reading CA certificates: {:?}", ca_file))?;
+        }
+        let stream = connector
+            .build()
+            .connect(&self.hostname, tcp)
+            .context(format!("TLS handshake with {} failed", self.hostname))?;
+
+        let mut session = Session {
+            stream,
+            buffer: Vec::new(),
+            next_tag: 1,
+            capabilities: Vec::new(),
+        };
+        match session.read_response()? {
+            Response::Data {
+                status: Status::Ok, ..
+            } => (),
+            greeting => {
+                return Err(anyhow::format_err!(
+                    "Unexpected IMAP greeting: {:?}",
+                    greeting
+                ))
+            }
+        }
+        session.run(&format!(
+            "LOGIN {} {}",
+            quote(&self.username)?,
+            quote(&self.password)?
+        ))?;
+        info!("IMAP logged in as {}", self.username);
+        for response in session.run("CAPABILITY")? {
+            if let Response::Capabilities(capabilities) = response {
+                session.capabilities = capabilities
+                    .into_iter()
+                    .filter_map(|capability| match capability {
+                        Capability::Atom(atom) => Some(atom.into_owned()),
+                        _ => None,
+                    })
+                    .collect();
+            }
+        }
+        session.run(&format!("SELECT {}", quote(&self.folder)?))?;
+        Ok(session)
+    }
+
+    fn session(&mut self) -> Result<&mut Session, Error> {
+        if self.session.is_none() {
+            self.session = Some(self.connect()?);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    /// Queues the attachments of the oldest unseen message that has any, so
+    /// that one message at a time is held in memory. Returns how many.
+    fn collect(&mut self) -> Result<usize, Error> {
+        let uids = self.session()?.search_unseen()?;
+        for uid in uids {
+            // Handed out and not confirmed yet
+            if self.unconfirmed.contains_key(&uid) {
+                continue;
+            }
+            let Some(size) = self.session()?.size(uid)? else {
+                continue;
+            };
+            if size > MAX_MESSAGE_SIZE {
+                warn!(
+                    "Message {} is {} bytes, over the maximum of {}, marking it seen",
+                    uid, size, MAX_MESSAGE_SIZE
+                );
+                self.session()?.mark_seen(uid)?;
+                continue;
+            }
+            let Some(message) = self.session()?.fetch(uid)? else {
+                continue;
+            };
+            let attachments = attachments(uid, &message);
+            if attachments.is_empty() {
+                warn!("Message {} has no bundle attachments, marking it seen", uid);
+                self.session()?.mark_seen(uid)?;
+                continue;
+            }
+            info!("Message {} has {} attachments", uid, attachments.len());
+            self.unconfirmed.insert(uid, attachments.len());
+            let collected = attachments.len();
+            self.attachments.extend(attachments);
+            return Ok(collected);
+        }
+        Ok(0)
+    }
+}
+
+/// The bundle attachments of a message, named `<uid>-<index>-<file name>`
+fn attachments(uid: u32, message: &[u8]) -> Vec<Attachment> {
+    let Some(message) = MessageParser::default().parse(message) else {
+        warn!("Message {} could not be parsed", uid);
+        return Vec::new();
+    };
+    mail::bundle_attachments(&message)
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| Attachment {
+            uid,
+            id: format!("{}-{}-{}", uid, index, part.name),
+            contents: part.contents,
+        })
+        .collect()
+}
+
+impl Source for ImapSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        info!("Next attachment requested");
+        let poll = self.options.poll.unwrap_or(DEFAULT_POLL_INTERVAL);
+        // Fetched again in full on the next round
+        Ok(poll_next(self, poll, |source| {
+            source.session = None;
+            source.attachments.clear();
+            source.unconfirmed.clear();
+        }))
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        loop {
+            if let Some(attachment) = self.attachments.pop_front() {
+                self.handed_out
+                    .insert(OsString::from(&attachment.id), attachment.uid);
+                return Ok(Some(Data {
+                    contents: attachment.contents,
+                    id: attachment.id.into(),
+                    subdirectory: PathBuf::new(),
+                }));
+            }
+            if self.collect().context("Failed to fetch messages")? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        let uid = self
+            .handed_out
+            .remove(&id)
+            .ok_or_else(|| anyhow::format_err!("Unknown attachment: {:?}", id))?;
+        let remaining = self.unconfirmed.entry(uid).or_insert(1);
+        *remaining -= 1;
+        if *remaining > 0 {
+            return Ok(());
+        }
+        self.unconfirmed.remove(&uid);
+
+        match &self.options.done {
+            Some(folder) => info!("Moving message {} to {}", uid, folder),
+            None => info!("Deleting message {}", uid),
+        }
+        let folder = self.options.done.clone();
+        if let Err(e) = self.session()?.remove(uid, folder.as_deref()) {
+            warn!("Failed to remove message {}, reconnecting: {:#}", uid, e);
+            self.session = None;
+            self.session()?.remove(uid, folder.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::mail::tests::{bundle, message};
+
+    #[test]
+    fn only_bundle_attachments_are_taken() {
+        let bundle = bundle();
+        let message = message(&[
+            ("logo.png", b"\x89PNG"),
+            ("../form.zip", &bundle),
+            ("signature.asc", b"-----BEGIN PGP SIGNATURE-----"),
+        ]);
+        let attachments = attachments(7, &message);
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].uid, 7);
+        assert_eq!(attachments[0].id, "7-0-form.zip");
+        assert_eq!(attachments[0].contents, bundle);
+    }
+
+    #[test]
+    fn messages_without_bundles_yield_nothing() {
+        assert!(attachments(1, &message(&[])).is_empty());
+        assert!(attachments(2, &message(&[("form.zip", b"PK")])).is_empty());
+        assert!(attachments(3, b"").is_empty());
+    }
+}
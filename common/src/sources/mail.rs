@@ -0,0 +1,106 @@
+//! Bundles carried in email messages
+
+use crate::bundle::{Bundle, MAX_BUNDLE_SIZE};
+use log::warn;
+use mail_parser::{Message, MimeHeaders};
+use std::path::Path;
+
+/// Room for a message carrying one bundle of `MAX_BUNDLE_SIZE`, base64 encoded
+/// with line breaks. Larger messages are not read.
+pub const MAX_MESSAGE_SIZE: usize = MAX_BUNDLE_SIZE / 2 * 3;
+
+/// A bundle attached to a message
+pub struct Part {
+    /// File name of the attachment without directories, `bundle` if it has none
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+/// The attachments that are bundles. Anything else mailed along, such as
+/// signatures and logos, is skipped so that it can not stop the consumer.
+pub fn bundle_attachments(message: &Message) -> Vec<Part> {
+    message
+        .attachments()
+        .filter_map(|attachment| {
+            let name = attachment
+                .attachment_name()
+                .and_then(|name| Path::new(name).file_name())
+                .and_then(|name| name.to_str())
+                .unwrap_or("bundle")
+                .to_string();
+            if attachment.contents().len() > MAX_BUNDLE_SIZE {
+                warn!(
+                    "Skipping attachment {}: {} bytes exceeds the maximum of {}",
+                    name,
+                    attachment.contents().len(),
+                    MAX_BUNDLE_SIZE
+                );
+                return None;
+            }
+            if let Err(e) = Bundle::from_bytes(attachment.contents()) {
+                warn!("Skipping attachment {}: {}", name, e);
+                return None;
+            }
+            Some(Part {
+                name,
+                contents: attachment.contents().to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Bundles and messages for the tests of the mail sources
+#[cfg(test)]
+pub(super) mod tests {
+    use crate::bundle::Metadata;
+    use crate::hybrid_kem::HybridPrivateKey;
+    use crate::recipient::Recipient;
+    use crate::rsa_keys::KeyPolicy;
+    use data_encoding::BASE64;
+
+    /// A valid bundle of its own
+    pub fn bundle() -> Vec<u8> {
+        let recipient = HybridPrivateKey::generate()
+            .unwrap()
+            .public()
+            .into_recipient(&KeyPolicy::default())
+            .unwrap();
+        crate::seal(
+            b"PK",
+            &Recipient::Hybrid(recipient),
+            Metadata::generate(None).unwrap(),
+        )
+        .unwrap()
+        .to_bytes()
+        .unwrap()
+    }
+
+    /// A message with a text body and the given attachments
+    pub fn message(attachments: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut message = "From: form@example.com\r\n\
+            To: bundles@example.com\r\n\
+            Subject: Bundles\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            See attached\r\n"
+            .to_string();
+        for (name, contents) in attachments {
+            message += &format!(
+                "--b\r\n\
+                Content-Type: application/octet-stream\r\n\
+                Content-Disposition: attachment; filename=\"{}\"\r\n\
+                Content-Transfer-Encoding: base64\r\n\
+                \r\n\
+                {}\r\n",
+                name,
+                BASE64.encode(contents)
+            );
+        }
+        message += "--b--\r\n";
+        message.into_bytes()
+    }
+}
@@ -0,0 +1,254 @@
+//! Bundles collected from a remote mailbox over HTTP, such as the one
+//! `queue-sender` posts to.
+//!
+//! `GET <url>` lists the pending bundles as a JSON array of IDs,
+//! `GET <url>/<id>` downloads one and `DELETE <url>/<id>` removes it once it
+//! has been delivered, or `POST <url>/<id>/ack` with `ack=post`. Bundles
+//! larger than `MAX_BUNDLE_SIZE` are left in the mailbox.
+
+use crate::bundle::MAX_BUNDLE_SIZE;
+use crate::sources::{poll_next, Data, Options, Source};
+use anyhow::{Context, Error};
+use log::{info, warn};
+use reqwest::blocking::{Client, Response};
+use reqwest::{Method, StatusCode};
+use std::collections::{HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Bundles can be large, the default of reqwest is 30 seconds
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How a delivered bundle is removed from the mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ack {
+    /// `DELETE <url>/<id>`
+    #[default]
+    Delete,
+    /// `POST <url>/<id>/ack`
+    Post,
+}
+
+impl FromStr for Ack {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "delete" => Ok(Ack::Delete),
+            "post" => Ok(Ack::Post),
+            _ => Err(anyhow::format_err!("Unknown acknowledgement method: {}", s)),
+        }
+    }
+}
+
+/// Settings given as query parameters of the source URL, for example
+/// `mailbox+https://host/mailbox?token_file=/etc/queue/token&poll=30`
+#[derive(Debug)]
+pub struct MailboxOptions {
+    /// File holding a bearer token for the mailbox
+    token_file: Option<PathBuf>,
+    /// How long to wait before listing an empty mailbox again
+    poll: Duration,
+    ack: Ack,
+}
+
+impl Default for MailboxOptions {
+    fn default() -> Self {
+        Self {
+            token_file: None,
+            poll: DEFAULT_POLL_INTERVAL,
+            ack: Ack::default(),
+        }
+    }
+}
+
+impl MailboxOptions {
+    pub fn from_options(options: Options) -> Result<Self, Error> {
+        let mut mailbox_options = Self::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "token_file" => mailbox_options.token_file = Some(PathBuf::from(value)),
+                "poll" => mailbox_options.poll = Duration::from_secs(value.parse()?),
+                "ack" => mailbox_options.ack = value.parse()?,
+                _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+            }
+        }
+        Ok(mailbox_options)
+    }
+}
+
+/// IDs end up as file names, so they must not contain paths
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id != "." && id != ".." && !id.contains(['/', '\0'])
+}
+
+/// Polls `mailbox+https://host/path` or `mailbox+http://host/path`
+pub struct MailboxSource {
+    client: Client,
+    /// The mailbox URL without the `mailbox+` prefix and the options
+    base: Url,
+    token: Option<String>,
+    options: MailboxOptions,
+    /// Listed but not yet downloaded
+    pending: VecDeque<String>,
+    /// Too large to download, skipped in listings
+    oversized: HashSet<String>,
+}
+
+impl MailboxSource {
+    pub fn new(url: &Url, options: MailboxOptions) -> Result<Self, Error> {
+        let mut base = Url::parse(url.as_str().trim_start_matches("mailbox+"))?;
+        base.set_query(None);
+        let token = match &options.token_file {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .context(format!("Failed to read token from {}", path.display()))?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+
+        let mut source = Self {
+            client,
+            base,
+            token,
+            options,
+            pending: VecDeque::new(),
+            oversized: HashSet::new(),
+        };
+        let ids = source
+            .list()
+            .context(format!("Failed to list mailbox {}", source.base))?;
+        source.pending.extend(ids);
+        Ok(source)
+    }
+
+    /// `<url>/<id>` with more path segments after it
+    fn item_url(&self, id: &str, rest: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("HTTP URLs have a path")
+            .pop_if_empty()
+            .push(id)
+            .extend(rest);
+        url
+    }
+
+    fn send(&self, method: Method, url: Url) -> Result<Response, Error> {
+        let mut request = self.client.request(method.clone(), url.clone());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .context(format!("HTTP request {} {} failed", method, url))
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let response = self
+            .send(Method::GET, self.base.clone())?
+            .error_for_status()?;
+        let ids: Vec<String> =
+            serde_json::from_slice(&response.bytes()?).context("Invalid mailbox listing")?;
+        Ok(ids
+            .into_iter()
+            .filter(|id| {
+                let valid = is_valid_id(id);
+                if !valid {
+                    warn!("Skipping bundle with invalid ID {:?}", id);
+                }
+                valid && !self.oversized.contains(id)
+            })
+            .collect())
+    }
+
+    /// `None` if the bundle is gone, taken by another consumer, or too large
+    fn download(&mut self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+        let response = self.send(Method::GET, self.item_url(id, &[]))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            info!("Bundle {} is gone, skipping", id);
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let too_large = response
+            .content_length()
+            .is_some_and(|length| length > MAX_BUNDLE_SIZE as u64);
+        let mut contents = Vec::new();
+        if !too_large {
+            response
+                .take(MAX_BUNDLE_SIZE as u64 + 1)
+                .read_to_end(&mut contents)?;
+        }
+        if too_large || contents.len() > MAX_BUNDLE_SIZE {
+            warn!(
+                "Bundle {} is larger than {} bytes, leaving it in the mailbox",
+                id, MAX_BUNDLE_SIZE
+            );
+            self.oversized.insert(id.to_string());
+            return Ok(None);
+        }
+        Ok(Some(contents))
+    }
+}
+
+impl Source for MailboxSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        info!("Next bundle requested");
+        let poll = self.options.poll;
+        // Listed again on the next round
+        Ok(poll_next(self, poll, |source| source.pending.clear()))
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        loop {
+            let Some(id) = self.pending.pop_front() else {
+                let ids = self.list().context("Failed to list mailbox")?;
+                if ids.is_empty() {
+                    return Ok(None);
+                }
+                self.pending.extend(ids);
+                continue;
+            };
+
+            if let Some(contents) = self
+                .download(&id)
+                .context(format!("Failed to download bundle {}", id))?
+            {
+                info!("Downloaded bundle {}", id);
+                return Ok(Some(Data {
+                    contents,
+                    id: id.into(),
+                    subdirectory: PathBuf::new(),
+                }));
+            }
+        }
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        let id = id
+            .into_string()
+            .map_err(|id| anyhow::format_err!("Invalid bundle ID: {:?}", id))?;
+        info!("Acknowledging bundle {}", id);
+        let response = match self.options.ack {
+            Ack::Delete => self.send(Method::DELETE, self.item_url(&id, &[]))?,
+            Ack::Post => self.send(Method::POST, self.item_url(&id, &["ack"]))?,
+        };
+        // Removed already, by us before a restart or by another consumer
+        if response.status() == StatusCode::NOT_FOUND {
+            warn!("Bundle {} was already gone", id);
+            return Ok(());
+        }
+        response
+            .error_for_status()
+            .context(format!("Failed to acknowledge bundle {}", id))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! Bundles delivered to a local Maildir, for hosts where an MTA receives them.
+//!
+//! Every attachment of a message that is a bundle is handed out. A message
+//! without attachments is taken as a bundle by itself if its body is one. Once
+//! all bundles of a message have been confirmed, it is moved to the `cur/`
+//! directory of the `done` Maildir. Messages without bundles and those over
+//! `MAX_MESSAGE_SIZE` are moved to the `rejected` Maildir instead, for someone
+//! to look at.
+
+use crate::bundle::Bundle;
+use crate::maildir::{seen_name, Maildir};
+use crate::sources::mail::{self, MAX_MESSAGE_SIZE};
+use crate::sources::{poll_next, Data, Options, Source};
+use anyhow::{Context, Error};
+use log::{info, warn};
+use mail_parser::MessageParser;
+use percent_encoding::percent_decode_str;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+const DEFAULT_DONE: &str = ".Processed";
+const DEFAULT_REJECTED: &str = ".Rejected";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Settings given as query parameters of the source URL, for example
+/// `maildir:///var/mail/bundles?done=/var/mail/processed&rejected=.Rejected&poll=30`
+#[derive(Debug)]
+pub struct MaildirOptions {
+    /// Maildir to move processed messages to, relative to the source one
+    /// unless absolute. Created if missing, on the same filesystem.
+    done: PathBuf,
+    /// Maildir to move messages without bundles to, like `done`
+    rejected: PathBuf,
+    /// How long to wait before scanning an empty Maildir again
+    poll: Duration,
+}
+
+impl Default for MaildirOptions {
+    fn default() -> Self {
+        Self {
+            done: PathBuf::from(DEFAULT_DONE),
+            rejected: PathBuf::from(DEFAULT_REJECTED),
+            poll: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+impl MaildirOptions {
+    pub fn from_options(options: Options) -> Result<Self, Error> {
+        let mut maildir_options = Self::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "done" => maildir_options.done = PathBuf::from(value),
+                "rejected" => maildir_options.rejected = PathBuf::from(value),
+                "poll" => maildir_options.poll = Duration::from_secs(value.parse()?),
+                _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+            }
+        }
+        Ok(maildir_options)
+    }
+}
+
+/// A bundle waiting to be handed out
+struct Pending {
+    /// The message it came from, relative to the Maildir
+    message: PathBuf,
+    id: String,
+    contents: Vec<u8>,
+}
+
+/// Messages in `new/` and `cur/` of `maildir:///path`, oldest first
+pub struct MaildirSource {
+    maildir: Maildir,
+    done: Maildir,
+    rejected: Maildir,
+    poll: Duration,
+    pending: VecDeque<Pending>,
+    /// Messages of handed out bundles
+    handed_out: HashMap<OsString, PathBuf>,
+    /// Bundles not yet confirmed per message
+    unconfirmed: HashMap<PathBuf, usize>,
+}
+
+impl MaildirSource {
+    pub fn new(url: &Url, options: MaildirOptions) -> Result<Self, Error> {
+        if url.host_str().is_some_and(|host| !host.is_empty()) {
+            return Err(anyhow::format_err!(
+                "Maildirs are local, not on a host: {}",
+                url
+            ));
+        }
+        let path = PathBuf::from(percent_decode_str(url.path()).decode_utf8()?.as_ref());
+        let maildir = Maildir::open(&path)?;
+        let done = path.join(&options.done);
+        let rejected = path.join(&options.rejected);
+        for moved_to in [&done, &rejected] {
+            if *moved_to == path {
+                return Err(anyhow::format_err!(
+                    "Messages moved to {:?} would be picked up again",
+                    moved_to
+                ));
+            }
+        }
+        let done = Maildir::create(&done)?;
+        let rejected = Maildir::create(&rejected)?;
+
+        Ok(Self {
+            maildir,
+            done,
+            rejected,
+            poll: options.poll,
+            pending: VecDeque::new(),
+            handed_out: HashMap::new(),
+            unconfirmed: HashMap::new(),
+        })
+    }
+
+    /// Messages in `new/` and `cur/` relative to the Maildir, oldest first
+    fn list(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut messages = Vec::new();
+        for subdirectory in ["new", "cur"] {
+            let directory = self.maildir.path().join(subdirectory);
+            for entry in
+                fs::read_dir(&directory).context(format!("Failed to list {:?}", directory))?
+            {
+                let entry = entry?;
+                // Dot files are not messages by the specification
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                messages.push((modified, Path::new(subdirectory).join(entry.file_name())));
+            }
+        }
+        messages.sort();
+        Ok(messages.into_iter().map(|(_, message)| message).collect())
+    }
+
+    /// Queues the bundles of the oldest message not handed out yet that has
+    /// any, so that one message at a time is held in memory. Returns how many.
+    fn collect(&mut self) -> Result<usize, Error> {
+        for message in self.list()? {
+            if self.unconfirmed.contains_key(&message) {
+                continue;
+            }
+            let file = match File::open(self.maildir.path().join(&message)) {
+                Ok(file) => file,
+                // Moved from new/ to cur/ in the meantime, found on the next round
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(Error::new(e).context(format!("Failed to read {:?}", message)))
+                }
+            };
+            let size = file
+                .metadata()
+                .context(format!("Failed to read {:?}", message))?
+                .len();
+            if size > MAX_MESSAGE_SIZE as u64 {
+                warn!(
+                    "Message {:?} is {} bytes, over the maximum of {}, rejecting it",
+                    message, size, MAX_MESSAGE_SIZE
+                );
+                self.move_to(&message, &self.rejected)?;
+                continue;
+            }
+            // Delivered messages are not written to, but nor is more read if one is
+            let mut contents = Vec::new();
+            file.take(size)
+                .read_to_end(&mut contents)
+                .context(format!("Failed to read {:?}", message))?;
+            let bundles = bundles(&message, &contents);
+            if bundles.is_empty() {
+                warn!("Message {:?} has no bundles, rejecting it", message);
+                self.move_to(&message, &self.rejected)?;
+                continue;
+            }
+            info!("Message {:?} has {} bundles", message, bundles.len());
+            self.unconfirmed.insert(message, bundles.len());
+            let collected = bundles.len();
+            self.pending.extend(bundles);
+            return Ok(collected);
+        }
+        Ok(0)
+    }
+
+    /// Moves a message into the `cur/` directory of another Maildir
+    fn move_to(&self, message: &Path, maildir: &Maildir) -> Result<(), Error> {
+        let name = message.file_name().unwrap_or_default().to_string_lossy();
+        let destination = maildir.path().join("cur").join(seen_name(&name));
+        info!("Moving message {:?} to {:?}", message, destination);
+        fs::rename(self.maildir.path().join(message), &destination)
+            .context(format!("Failed to move message {:?}", message))
+    }
+}
+
+/// The bundles of a message, named `<message name>-<index>-<file name>`
+fn bundles(message: &Path, contents: &[u8]) -> Vec<Pending> {
+    let Some(parsed) = MessageParser::default().parse(contents) else {
+        warn!("Message {:?} could not be parsed", message);
+        return Vec::new();
+    };
+    let mut parts = mail::bundle_attachments(&parsed);
+    if parts.is_empty() {
+        let body = parsed.root_part().contents();
+        if Bundle::from_bytes(body).is_ok() {
+            parts.push(mail::Part {
+                name: "bundle".to_string(),
+                contents: body.to_vec(),
+            });
+        }
+    }
+
+    // Without the flags, which change when a client reads the message
+    let name = message.file_name().unwrap_or_default().to_string_lossy();
+    let name = name.split(':').next().unwrap_or_default();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| Pending {
+            message: message.to_path_buf(),
+            id: format!("{}-{}-{}", name, index, part.name),
+            contents: part.contents,
+        })
+        .collect()
+}
+
+impl Source for MaildirSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        info!("Next message requested");
+        let poll = self.poll;
+        Ok(poll_next(self, poll, |_| ()))
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        loop {
+            if let Some(pending) = self.pending.pop_front() {
+                self.handed_out
+                    .insert(OsString::from(&pending.id), pending.message);
+                return Ok(Some(Data {
+                    contents: pending.contents,
+                    id: pending.id.into(),
+                    subdirectory: PathBuf::new(),
+                }));
+            }
+            if self.collect().context("Failed to scan Maildir")? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        let message = self
+            .handed_out
+            .remove(&id)
+            .ok_or_else(|| anyhow::format_err!("Unknown bundle: {:?}", id))?;
+        let remaining = self.unconfirmed.entry(message.clone()).or_insert(1);
+        *remaining -= 1;
+        if *remaining > 0 {
+            return Ok(());
+        }
+        self.unconfirmed.remove(&message);
+        self.move_to(&message, &self.done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::mail::tests::{bundle, message};
+    use data_encoding::BASE64;
+
+    fn messages(maildir: &Path, subdirectory: &str) -> usize {
+        fs::read_dir(maildir.join(subdirectory)).unwrap().count()
+    }
+
+    #[test]
+    fn bundles_are_collected_one_message_at_a_time() {
+        let path = std::env::temp_dir().join(format!("maildir-source-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let maildir = Maildir::create(&path).unwrap();
+        let bundles = [bundle(), bundle()];
+        maildir
+            .deliver(&message(&[("form.zip", &bundles[0])]))
+            .unwrap();
+        maildir.deliver(&message(&[("logo.png", b"PNG")])).unwrap();
+        maildir
+            .deliver(&message(&[("form.zip", &bundles[1])]))
+            .unwrap();
+        // Sparse, so not actually written
+        File::create(path.join("new/oversized"))
+            .unwrap()
+            .set_len(MAX_MESSAGE_SIZE as u64 + 1)
+            .unwrap();
+
+        let url = Url::parse(&format!("maildir://{}", path.display())).unwrap();
+        let mut source = MaildirSource::new(&url, MaildirOptions::default()).unwrap();
+        let mut received = Vec::new();
+        while let Some(data) = source.next_available().unwrap() {
+            // The other message with a bundle is left until this one is handed out
+            assert!(source.pending.is_empty());
+            assert_eq!(source.unconfirmed.len(), 1);
+            received.push(data.contents);
+            source.confirm(data.id).unwrap();
+        }
+        received.sort();
+        let mut sent = bundles.to_vec();
+        sent.sort();
+        assert_eq!(received, sent);
+
+        assert_eq!(messages(&path, "new") + messages(&path, "cur"), 0);
+        assert_eq!(messages(&path.join(DEFAULT_DONE), "cur"), 2);
+        assert_eq!(messages(&path.join(DEFAULT_REJECTED), "cur"), 2);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn a_message_can_be_a_bundle_by_itself() {
+        let bundle = bundle();
+        let message = format!(
+            "From: form@example.com\r\n\
+            Content-Type: application/octet-stream\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            {}\r\n",
+            BASE64.encode(&bundle)
+        );
+        let pending = bundles(Path::new("cur/1700000000.1.host:2,S"), message.as_bytes());
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, "1700000000.1.host-0-bundle");
+        assert_eq!(pending[0].contents, bundle);
+    }
+}
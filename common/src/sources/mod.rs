@@ -1,27 +1,268 @@
-use crate::sources::file::FileSource;
-use crate::sources::ssh::SshSource;
-use std::ffi::OsString;
+use crate::sources::claim::ClaimOptions;
+use crate::sources::file::{FileSource, DEFAULT_MIN_AGE};
+use crate::sources::http::{HttpOptions, HttpSource};
+use crate::sources::imap::{ImapOptions, ImapSource};
+use crate::sources::mailbox::{MailboxOptions, MailboxSource};
+use crate::sources::maildir::{MaildirOptions, MaildirSource};
+use crate::sources::poll::PollingFileSource;
+use crate::sources::s3::{S3Options, S3Source};
+use crate::sources::ssh::{SshOptions, SshSource};
+use crate::sources::stdin::StdinSource;
+use anyhow::Context;
+use log::warn;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use url::Url;
 
+pub use crate::sources::file::settled_files;
+
+/// A blackholed connection has to fail instead of hanging forever
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) mod claim;
 mod file;
+mod http;
+mod imap;
+mod mail;
+mod mailbox;
+mod maildir;
+mod poll;
+mod s3;
 mod ssh;
+mod stdin;
 
 #[derive(Debug)]
 pub struct Data {
     pub contents: Vec<u8>,
+    /// Passed back to `Source::confirm`, ends in the file name
     pub id: OsString,
+    /// Directory of the file relative to the watched one, empty at the top level
+    pub subdirectory: PathBuf,
+}
+
+impl Data {
+    /// A file in a local directory, identified by its path relative to it
+    fn local(relative: PathBuf, contents: Vec<u8>) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            contents,
+            subdirectory: relative.parent().map(Path::to_path_buf).unwrap_or_default(),
+            id: relative.into_os_string(),
+        })
+    }
+
+    pub fn file_name(&self) -> Result<&OsStr, anyhow::Error> {
+        Path::new(&self.id)
+            .file_name()
+            .ok_or_else(|| anyhow::Error::msg("Unable to get input filename"))
+    }
 }
 
+/// Sources that connect or list when they are created fail early on
+/// configuration errors, later failures are retried.
 pub trait Source {
+    /// Waits until there is something to hand out
     fn next(&mut self) -> Result<Data, anyhow::Error>;
-    fn confirm(&self, id: OsString) -> Result<(), anyhow::Error>;
+    /// Hands out what is there without waiting, `None` once there is nothing.
+    /// Errors are returned instead of retried.
+    fn next_available(&mut self) -> Result<Option<Data>, anyhow::Error>;
+    fn confirm(&mut self, id: OsString) -> Result<(), anyhow::Error>;
+}
+
+/// `Source::next` for sources that poll: waits `poll` between rounds of
+/// `next_available` that find nothing. Failures are logged and retried on the
+/// next round, after `reset` has dropped what the failed one left behind.
+fn poll_next<S: Source>(source: &mut S, poll: Duration, reset: impl Fn(&mut S)) -> Data {
+    loop {
+        match source.next_available() {
+            Ok(Some(data)) => return data,
+            Ok(None) => thread::sleep(poll),
+            Err(e) => {
+                warn!("{:#}, retrying", e);
+                reset(source);
+                thread::sleep(poll);
+            }
+        }
+    }
+}
+
+/// Hands everything from `source` to `handle` and confirms it once handled,
+/// until an error or, with `once`, until nothing is left. Returns how many
+/// were handled.
+pub fn process(
+    source: &mut dyn Source,
+    once: bool,
+    mut handle: impl FnMut(&Data) -> Result<(), anyhow::Error>,
+) -> Result<usize, anyhow::Error> {
+    let mut handled = 0;
+    loop {
+        let data = if once {
+            match source.next_available()? {
+                Some(data) => data,
+                None => return Ok(handled),
+            }
+        } else {
+            source.next()?
+        };
+        handle(&data)?;
+        source.confirm(data.id)?;
+        handled += 1;
+    }
+}
+
+/// Whether `s` reads standard input, which is always processed once
+pub fn is_stdin(s: &str) -> bool {
+    s == "-" || s.starts_with("stdin:")
 }
 
+/// Query parameters of a source URL
+type Options = Vec<(String, String)>;
+
+/// A source URL: `file:///path` for a local directory or
+/// `sftp://[user@]host[:port]/path` for a directory over SFTP, where `/~/path`
+/// is relative to the home directory, and `http://host:port/path` or
+/// `http+unix:///path/to/socket` to listen for uploads, see `HttpSource`, and
+/// `mailbox+https://host/path` to collect bundles from a remote mailbox, see
+/// `mailbox`, `s3://bucket/prefix` for objects in a bucket,
+/// `imaps://user@host/folder` for attachments mailed to a folder and
+/// `maildir:///path` for messages delivered to a local Maildir. A plain path is
+/// taken as a local directory without options, and `-` or `stdin:<file name>`
+/// reads standard input, see `StdinSource`.
+///
+/// Options are given as query parameters: `worker` and `claim_timeout` for
+/// sharing the queue between workers, see `claim`, and `SshOptions`,
+/// `HttpOptions`, `MailboxOptions`, `S3Options`, `ImapOptions` and
+/// `MaildirOptions` for the others. Local directories take `ready=rename` to
+/// choose which events mean a file is complete, `poll=30` to scan every 30
+/// seconds instead and `recursive=true` to include subdirectories. Draining a
+/// directory with `Source::next_available` has no events to wait for, so files
+/// are only taken once they have not changed for `min_age=10` seconds, or for a
+/// poll interval.
 pub fn from_string(s: &str) -> Result<Box<dyn Source>, anyhow::Error> {
-    if s.starts_with('/') {
-        Ok(Box::new(FileSource::new(s)))
-    } else if s.contains(':') {
-        Ok(Box::new(SshSource::new(s)?))
-    } else {
-        panic!("Invalid source specification");
+    if is_stdin(s) {
+        let name = s.strip_prefix("stdin:").unwrap_or_default();
+        return Ok(Box::new(StdinSource::new(name)?));
+    }
+    if !s.contains("://") {
+        if let Some(url) = legacy_sftp_url(s) {
+            return Err(anyhow::format_err!(
+                "SFTP sources are given as URLs, use {} instead of {}",
+                url,
+                s
+            ));
+        }
+        let path = std::path::absolute(s).context(format!("Invalid source path: {}", s))?;
+        return file_source(path, Vec::new());
+    }
+
+    let url = Url::parse(s).context(format!("Invalid source URL: {}", s))?;
+    let options = url.query_pairs().into_owned().collect();
+    match url.scheme() {
+        "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow::format_err!("Invalid file URL: {}", s))?;
+            file_source(path, options)
+        }
+        "sftp" => Ok(Box::new(SshSource::new(
+            &url,
+            SshOptions::from_options(options)?,
+        )?)),
+        "http" | "http+unix" => Ok(Box::new(HttpSource::new(
+            &url,
+            HttpOptions::from_options(options)?,
+        )?)),
+        "mailbox+http" | "mailbox+https" => Ok(Box::new(MailboxSource::new(
+            &url,
+            MailboxOptions::from_options(options)?,
+        )?)),
+        "s3" => Ok(Box::new(S3Source::new(
+            &url,
+            S3Options::from_options(options)?,
+        )?)),
+        "imaps" => Ok(Box::new(ImapSource::new(
+            &url,
+            ImapOptions::from_options(options)?,
+        )?)),
+        "maildir" => Ok(Box::new(MaildirSource::new(
+            &url,
+            MaildirOptions::from_options(options)?,
+        )?)),
+        scheme => Err(anyhow::format_err!("Unsupported source scheme: {}", scheme)),
+    }
+}
+
+/// The URL for a `[user@]host:path` source, the SFTP syntax before sources
+/// were URLs. A local path that looks like one has to start with `./`.
+fn legacy_sftp_url(s: &str) -> Option<String> {
+    let (connection, path) = s.split_once(':')?;
+    if connection.is_empty() || connection.contains('/') {
+        return None;
+    }
+    match path.strip_prefix('/') {
+        Some(path) => Some(format!("sftp://{}/{}", connection, path)),
+        None => Some(format!("sftp://{}/~/{}", connection, path)),
+    }
+}
+
+fn file_source(path: PathBuf, options: Options) -> Result<Box<dyn Source>, anyhow::Error> {
+    let mut readiness = None;
+    let mut poll_interval = None;
+    let mut recursive = false;
+    let mut min_age = None;
+    let mut claim = ClaimOptions::default();
+    for (key, value) in options {
+        if claim.set(&key, &value)? {
+            continue;
+        }
+        match key.as_str() {
+            "ready" => readiness = Some(value.parse()?),
+            "poll" => poll_interval = Some(Duration::from_secs(value.parse()?)),
+            "recursive" => recursive = value.parse()?,
+            "min_age" => min_age = Some(Duration::from_secs(value.parse()?)),
+            _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+        }
+    }
+    if poll_interval.is_some() && min_age.is_some() {
+        return Err(anyhow::Error::msg(
+            "Polling sources wait for files to settle, `min_age` does not apply",
+        ));
+    }
+    match (poll_interval, readiness) {
+        (Some(_), Some(_)) => Err(anyhow::Error::msg(
+            "Polling sources do not use events, `ready` does not apply",
+        )),
+        (Some(interval), None) => Ok(Box::new(PollingFileSource::new(
+            path, interval, recursive, claim,
+        ))),
+        (None, readiness) => Ok(Box::new(FileSource::new(
+            path,
+            readiness.unwrap_or_default(),
+            recursive,
+            min_age.unwrap_or(DEFAULT_MIN_AGE),
+            claim,
+        )?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_sftp_sources_are_pointed_to_urls() {
+        assert_eq!(
+            legacy_sftp_url("user@host:/srv/queue").as_deref(),
+            Some("sftp://user@host/srv/queue")
+        );
+        assert_eq!(
+            legacy_sftp_url("host:queue").as_deref(),
+            Some("sftp://host/~/queue")
+        );
+        assert_eq!(legacy_sftp_url("./host:queue"), None);
+        assert_eq!(legacy_sftp_url("/srv/queue"), None);
+        let error = from_string("user@host:/srv/queue").err().unwrap();
+        assert!(error.to_string().contains("sftp://user@host/srv/queue"));
     }
 }
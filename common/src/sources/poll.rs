@@ -0,0 +1,140 @@
+use crate::sources::claim::{ClaimOptions, LocalClaims};
+use crate::sources::file::regular_files;
+use crate::sources::{Data, Source};
+use anyhow::Context;
+use anyhow::Error;
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Size and modification time of a file, and since when they have not changed
+struct Observation {
+    size: u64,
+    modified: SystemTime,
+    since: Instant,
+}
+
+/// Scans a directory at an interval instead of waiting for events, for network
+/// filesystems where writes from other hosts generate none. A file is ready once
+/// its size and modification time have not changed for a whole interval.
+pub struct PollingFileSource {
+    path: PathBuf,
+    interval: Duration,
+    recursive: bool,
+    observed: HashMap<PathBuf, Observation>,
+    /// Whether files have been observed for an interval, see `next_available`
+    settled: bool,
+    claims: LocalClaims,
+    recovered: VecDeque<PathBuf>,
+}
+
+impl PollingFileSource {
+    pub fn new(path: PathBuf, interval: Duration, recursive: bool, claim: ClaimOptions) -> Self {
+        Self {
+            claims: LocalClaims::new(&path, claim),
+            path,
+            interval,
+            recursive,
+            observed: HashMap::new(),
+            settled: false,
+            recovered: VecDeque::new(),
+        }
+    }
+
+    /// Updates the observations and returns the stable files, oldest first
+    fn scan(&mut self) -> Result<Vec<PathBuf>, Error> {
+        let now = Instant::now();
+        let mut observed = HashMap::new();
+        let mut ready = Vec::new();
+        for (path, metadata) in regular_files(&self.path, self.recursive)? {
+            let size = metadata.len();
+            let modified = metadata.modified()?;
+            let since = match self.observed.remove(&path) {
+                Some(previous) if previous.size == size && previous.modified == modified => {
+                    previous.since
+                }
+                _ => now,
+            };
+            if now.duration_since(since) >= self.interval {
+                ready.push((modified, path.clone()));
+            }
+            observed.insert(
+                path,
+                Observation {
+                    size,
+                    modified,
+                    since,
+                },
+            );
+        }
+        self.observed = observed;
+
+        ready.sort();
+        Ok(ready.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+impl Source for PollingFileSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        info!("Next file requested");
+        let relative = 'found: loop {
+            if let Some(relative) = self.recovered.pop_front() {
+                break relative;
+            }
+            self.recovered.extend(self.claims.recover()?);
+            if !self.recovered.is_empty() {
+                continue;
+            }
+
+            let ready = self
+                .scan()
+                .context(format!("Failed to scan {}", self.path.display()))?;
+            for fname in ready {
+                info!("New file available: {}", fname.to_string_lossy());
+                let relative = fname.strip_prefix(&self.path)?.to_path_buf();
+                match self.claims.claim(&relative)? {
+                    Some(_) => break 'found relative,
+                    None => info!(".. removed or claimed by another worker, skipping"),
+                }
+            }
+            thread::sleep(self.interval);
+        };
+
+        self.claims.read(relative)
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        info!("Next available file requested");
+        self.recovered.extend(self.claims.recover()?);
+        if let Some(relative) = self.recovered.pop_front() {
+            return self.claims.read(relative).map(Some);
+        }
+
+        // Files are only ready once they have been seen unchanged for an
+        // interval, those still being written are left for the next run
+        if !self.settled {
+            self.scan()
+                .context(format!("Failed to scan {}", self.path.display()))?;
+            thread::sleep(self.interval);
+            self.settled = true;
+        }
+        let ready = self
+            .scan()
+            .context(format!("Failed to scan {}", self.path.display()))?;
+        for fname in ready {
+            let relative = fname.strip_prefix(&self.path)?.to_path_buf();
+            if self.claims.claim(&relative)?.is_some() {
+                return self.claims.read(relative).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        info!("Removing file: {}", id.to_string_lossy());
+        self.claims.release(Path::new(&id))
+    }
+}
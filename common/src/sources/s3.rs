@@ -0,0 +1,178 @@
+use crate::bundle::MAX_BUNDLE_SIZE;
+use crate::s3::{bucket_and_prefix, directory_prefix, Bucket, BucketOptions};
+use crate::sources::{poll_next, Data, Options, Source};
+use anyhow::{Context, Error};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings given as query parameters of the source URL, for example
+/// `s3://bucket/incoming?endpoint=http://localhost:9000&done=processed`
+#[derive(Debug)]
+pub struct S3Options {
+    bucket: BucketOptions,
+    /// How long to wait before listing an empty prefix again
+    poll: Duration,
+    /// Prefix to move confirmed objects to instead of deleting them
+    done: Option<String>,
+}
+
+impl Default for S3Options {
+    fn default() -> Self {
+        Self {
+            bucket: BucketOptions::default(),
+            poll: DEFAULT_POLL_INTERVAL,
+            done: None,
+        }
+    }
+}
+
+impl S3Options {
+    pub fn from_options(options: Options) -> Result<Self, Error> {
+        let mut s3_options = Self::default();
+        for (key, value) in options {
+            if s3_options.bucket.set(&key, &value)? {
+                continue;
+            }
+            match key.as_str() {
+                "poll" => s3_options.poll = Duration::from_secs(value.parse()?),
+                "done" => s3_options.done = Some(directory_prefix(&value)),
+                _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+            }
+        }
+        Ok(s3_options)
+    }
+}
+
+/// Keys become file names and subdirectories, so they must stay below the prefix
+fn is_valid_key(relative: &str) -> bool {
+    !relative.is_empty()
+        && !relative.ends_with('/')
+        && Path::new(relative)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Objects under a prefix of a bucket, `s3://bucket/prefix`, oldest first.
+/// Objects in "subdirectories" of the prefix keep them as their subdirectory.
+pub struct S3Source {
+    bucket: Bucket,
+    prefix: String,
+    poll: Duration,
+    done: Option<String>,
+    /// Keys relative to the prefix, listed but not yet downloaded
+    pending: VecDeque<String>,
+}
+
+impl S3Source {
+    pub fn new(url: &Url, options: S3Options) -> Result<Self, Error> {
+        let (name, prefix) = bucket_and_prefix(url)?;
+        if let Some(done) = &options.done {
+            if done.starts_with(&prefix) {
+                return Err(anyhow::format_err!(
+                    "Objects moved to {} would be picked up again from {}",
+                    done,
+                    prefix
+                ));
+            }
+        }
+
+        let mut source = Self {
+            bucket: Bucket::new(&name, options.bucket)?,
+            prefix,
+            poll: options.poll,
+            done: options.done,
+            pending: VecDeque::new(),
+        };
+        let keys = source.list().context(format!("Failed to list {}", url))?;
+        source.pending.extend(keys);
+        Ok(source)
+    }
+
+    /// Relative keys of the objects under the prefix, oldest first. Objects over
+    /// `MAX_BUNDLE_SIZE` are left in the bucket.
+    fn list(&mut self) -> Result<Vec<String>, Error> {
+        let mut objects = self.bucket.list(&self.prefix)?;
+        objects.sort_by(|a, b| a.last_modified.cmp(&b.last_modified));
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| {
+                let relative = object.key.strip_prefix(&self.prefix)?.to_string();
+                if !is_valid_key(&relative) {
+                    // Directory markers are expected, anything else is odd
+                    if !object.key.ends_with('/') {
+                        warn!("Skipping object with invalid key {:?}", object.key);
+                    }
+                    return None;
+                }
+                if object.size > MAX_BUNDLE_SIZE as u64 {
+                    warn!(
+                        "Skipping {}, {} bytes is larger than {}",
+                        object.key, object.size, MAX_BUNDLE_SIZE
+                    );
+                    return None;
+                }
+                Some(relative)
+            })
+            .collect())
+    }
+}
+
+impl Source for S3Source {
+    fn next(&mut self) -> Result<Data, Error> {
+        info!("Next object requested");
+        let poll = self.poll;
+        // Listed again on the next round
+        Ok(poll_next(self, poll, |source| source.pending.clear()))
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        loop {
+            let Some(relative) = self.pending.pop_front() else {
+                let keys = self.list().context("Failed to list objects")?;
+                if keys.is_empty() {
+                    return Ok(None);
+                }
+                self.pending.extend(keys);
+                continue;
+            };
+
+            let key = format!("{}{}", self.prefix, relative);
+            match self
+                .bucket
+                .get(&key, MAX_BUNDLE_SIZE)
+                .context(format!("Failed to download {}", key))?
+            {
+                Some(contents) => {
+                    info!("Downloaded {}", key);
+                    return Data::local(PathBuf::from(relative), contents).map(Some);
+                }
+                None => info!("{} is gone, skipping", key),
+            }
+        }
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        let relative = id
+            .into_string()
+            .map_err(|id| anyhow::format_err!("Invalid object key: {:?}", id))?;
+        let key = format!("{}{}", self.prefix, relative);
+        if let Some(done) = &self.done {
+            let destination = format!("{}{}", done, relative);
+            info!("Moving {} to {}", key, destination);
+            self.bucket
+                .copy(&key, &destination)
+                .context(format!("Failed to copy {}", key))?;
+        } else {
+            info!("Removing {}", key);
+        }
+        self.bucket
+            .delete(&key)
+            .context(format!("Failed to delete {}", key))
+    }
+}
@@ -1,116 +1,570 @@
-use crate::sources::{Data, Source};
-use anyhow::Error;
-use log::info;
-use ssh2::{Session, Sftp};
-use std::ffi::OsString;
-use std::io::Read;
+use crate::sources::claim::{ClaimOptions, Heartbeat, PROCESSING_DIR, RECOVERY_INTERVAL};
+use crate::sources::{Data, Options, Source, SESSION_TIMEOUT};
+use crate::watch::is_ignored;
+use anyhow::{Context, Error};
+use data_encoding::BASE64_NOPAD;
+use log::{info, warn};
+use openssl::rand::rand_bytes;
+use percent_encoding::percent_decode_str;
+use ssh2::{
+    CheckResult, ErrorCode, FileStat, HashType, KnownHostFileKind, RenameFlags, Session, Sftp,
+};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
 use std::net::TcpStream;
-use std::path::PathBuf;
-use std::time::Duration;
-use std::{env, thread};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fs, thread};
+use url::{Host, Url};
 
-fn split2(s: &str, pattern: char) -> Result<(String, String), Error> {
-    let mut v: Vec<&str> = s.split(pattern).collect();
-    if v.len() != 2 {
-        return Err(Error::msg("Unexpected split"));
-    }
-    let second = v.pop().unwrap().to_string();
-    let first = v.pop().unwrap().to_string();
-    Ok((first, second))
-}
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DEFAULT_MIN_AGE: Duration = Duration::from_secs(10);
+/// `LIBSSH2_FX_NO_SUCH_FILE`
+const SFTP_NO_SUCH_FILE: i32 = 2;
 
 struct RemoteParameters {
     username: Option<String>,
     hostname: String,
+    port: u16,
     path: String,
 }
 
-fn parse_connection_string(connection: &str) -> Result<RemoteParameters, Error> {
-    let (connection_string, path) = split2(connection, ':')?;
-
-    let username;
-    let hostname;
-
-    if connection_string.contains('@') {
-        let (un, hn) = split2(&connection_string, '@')?;
-        username = Some(un);
-        hostname = hn;
-    } else {
-        username = None;
-        hostname = connection_string;
-    }
+/// `sftp://[user@]host[:port]/path`, where a path starting with `/~/` is
+/// relative to the home directory
+fn parse_url(url: &Url) -> Result<RemoteParameters, Error> {
+    let hostname = match url.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        Some(Host::Ipv4(address)) => address.to_string(),
+        Some(Host::Ipv6(address)) => address.to_string(),
+        None => return Err(anyhow::format_err!("No host in {}", url)),
+    };
+    let username = match url.username() {
+        "" => None,
+        username => Some(percent_decode_str(username).decode_utf8()?.into_owned()),
+    };
+    let path = percent_decode_str(url.path()).decode_utf8()?;
+    let path = match path.strip_prefix("/~/") {
+        Some(relative) => relative.to_string(),
+        None => path.into_owned(),
+    };
 
     Ok(RemoteParameters {
         username,
         hostname,
+        port: url.port().unwrap_or(22),
         path,
     })
 }
 
+/// Connection settings given as query parameters of the source URL, for example
+/// `sftp://user@host/path?agent=true`
+#[derive(Debug, Default)]
+pub struct SshOptions {
+    /// Private key file, `~/.ssh/id_ed25519` by default
+    identity: Option<PathBuf>,
+    /// File holding the passphrase of the private key
+    passphrase_file: Option<PathBuf>,
+    /// Authenticate with ssh-agent instead of a key file
+    agent: bool,
+    /// `~/.ssh/known_hosts` by default, not used with a pinned fingerprint
+    known_hosts: Option<PathBuf>,
+    /// Expected host key, as printed by `ssh-keygen -l`: `SHA256:...`
+    fingerprint: Option<String>,
+    /// How long ago a file must have been modified to count as fully uploaded
+    min_age: Option<Duration>,
+    claim: ClaimOptions,
+}
+
+impl SshOptions {
+    pub fn from_options(options: Options) -> Result<Self, Error> {
+        let mut ssh_options = Self::default();
+        for (key, value) in options {
+            if ssh_options.claim.set(&key, &value)? {
+                continue;
+            }
+            let value = value.as_str();
+            match key.as_str() {
+                "identity" => ssh_options.identity = Some(PathBuf::from(value)),
+                "passphrase_file" => ssh_options.passphrase_file = Some(PathBuf::from(value)),
+                "agent" => ssh_options.agent = value.parse()?,
+                "known_hosts" => ssh_options.known_hosts = Some(PathBuf::from(value)),
+                "fingerprint" => ssh_options.fingerprint = Some(value.to_string()),
+                "min_age" => ssh_options.min_age = Some(Duration::from_secs(value.parse()?)),
+                _ => return Err(anyhow::format_err!("Unknown source option: {}", key)),
+            }
+        }
+        Ok(ssh_options)
+    }
+}
+
+fn home_file(path: &str) -> Result<PathBuf, Error> {
+    let home = env::var("HOME").context(format!("$HOME is not set, can not locate ~/{}", path))?;
+    Ok(PathBuf::from(home).join(path))
+}
+
+/// Refuses to continue unless the host key is the pinned one, or is listed in known_hosts
+fn verify_host_key(
+    sess: &Session,
+    hostname: &str,
+    port: u16,
+    options: &SshOptions,
+) -> Result<(), Error> {
+    if let Some(expected) = &options.fingerprint {
+        let hash = sess
+            .host_key_hash(HashType::Sha256)
+            .ok_or_else(|| Error::msg("Server sent no host key"))?;
+        let fingerprint = format!("SHA256:{}", BASE64_NOPAD.encode(hash));
+        if fingerprint != *expected {
+            return Err(anyhow::format_err!(
+                "Host key fingerprint of {} is {}, expected {}",
+                hostname,
+                fingerprint,
+                expected
+            ));
+        }
+        return Ok(());
+    }
+
+    let known_hosts_file = match &options.known_hosts {
+        Some(path) => path.clone(),
+        None => home_file(".ssh/known_hosts")?,
+    };
+    let mut known_hosts = sess.known_hosts()?;
+    known_hosts
+        .read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)
+        .context(format!("Error reading {:?}", known_hosts_file))?;
+    let (key, _) = sess
+        .host_key()
+        .ok_or_else(|| Error::msg("Server sent no host key"))?;
+    match known_hosts.check_port(hostname, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow::format_err!(
+            "Host key of {} does not match {:?}, possible man-in-the-middle",
+            hostname,
+            known_hosts_file
+        )),
+        CheckResult::NotFound => Err(anyhow::format_err!(
+            "{} is not in {:?}",
+            hostname,
+            known_hosts_file
+        )),
+        CheckResult::Failure => Err(Error::msg("Host key check failed")),
+    }
+}
+
+fn authenticate(sess: &Session, username: &str, options: &SshOptions) -> Result<(), Error> {
+    if options.agent {
+        sess.userauth_agent(username)
+            .context("ssh-agent authentication failed")?;
+    } else {
+        let private_key = match &options.identity {
+            Some(path) => path.clone(),
+            None => home_file(".ssh/id_ed25519")?,
+        };
+        let passphrase = match &options.passphrase_file {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .context(format!("Error reading passphrase: {:?}", path))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+            ),
+            None => None,
+        };
+        sess.userauth_pubkey_file(
+            username,
+            None,
+            Path::new(&private_key),
+            passphrase.as_deref(),
+        )
+        .context(format!("Authentication with {:?} failed", private_key))?;
+    }
+    if !sess.authenticated() {
+        return Err(Error::msg("Authentication failed"));
+    }
+    Ok(())
+}
+
 pub struct SshSource {
-    sftp: Sftp,
-    directory: PathBuf,
+    remote_parameters: RemoteParameters,
+    username: String,
+    options: SshOptions,
+    /// Shared with the heartbeat, `None` while reconnecting
+    sftp: Arc<Mutex<Option<Arc<Sftp>>>>,
+    heartbeat: Heartbeat,
+    last_recovery: Option<Instant>,
+    /// Names of files taken over from crashed workers, already claimed
+    recovered: VecDeque<OsString>,
 }
 
 impl SshSource {
-    pub fn new(url: &str) -> Result<Self, Error> {
-        let remote_parameters = parse_connection_string(url)?;
+    pub fn new(url: &Url, options: SshOptions) -> Result<Self, Error> {
+        let remote_parameters = parse_url(url)?;
 
-        let username = if let Some(username) = remote_parameters.username {
-            username
-        } else {
-            env::var("USER").expect("Unable to get current $USER")
+        let username = match &remote_parameters.username {
+            Some(username) => username.clone(),
+            None => env::var("USER").context("No username given and $USER is not set")?,
         };
 
-        let private_key = PathBuf::new()
-            .join(env::var("HOME").expect("Unable to read $HOME"))
-            .join(".ssh/id_ed25519");
+        let sftp = Arc::new(Mutex::new(None::<Arc<Sftp>>));
+        let shared = Arc::clone(&sftp);
+        let heartbeat = Heartbeat::start(options.claim.timeout, move |path| {
+            let sftp = shared.lock().unwrap().clone();
+            match sftp {
+                Some(sftp) => touch(&sftp, path),
+                // Touched again once reconnected
+                None => Ok(()),
+            }
+        });
+        let source = Self {
+            remote_parameters,
+            username,
+            options,
+            sftp,
+            heartbeat,
+            last_recovery: None,
+            recovered: VecDeque::new(),
+        };
+        *source.sftp.lock().unwrap() = Some(Arc::new(source.connect()?));
+        Ok(source)
+    }
+
+    fn connect(&self) -> Result<Sftp, Error> {
+        let hostname = &self.remote_parameters.hostname;
+        let port = self.remote_parameters.port;
 
         info!("Connnecting SSH");
-        let tcp = TcpStream::connect(format!("{}:22", remote_parameters.hostname)).unwrap();
-        let mut sess = Session::new().unwrap();
+        let tcp = TcpStream::connect((hostname.as_str(), port))
+            .context(format!("Error connecting to {}:{}", hostname, port))?;
+        let mut sess = Session::new()?;
         sess.set_tcp_stream(tcp);
+        sess.set_timeout(SESSION_TIMEOUT.as_millis() as u32);
         sess.handshake()?;
+        verify_host_key(&sess, hostname, port, &self.options)?;
         info!("SSH connected");
-        sess.userauth_pubkey_file(&username, None, &private_key, None)?;
-        if !sess.authenticated() {
-            return Err(Error::msg("Authentication failed"));
-        }
+        authenticate(&sess, &self.username, &self.options)?;
         info!("SSH authenticated");
 
         let sftp = sess.sftp()?;
         info!("SFTP session open");
+        Ok(sftp)
+    }
 
-        Ok(Self {
-            sftp,
-            directory: PathBuf::from(remote_parameters.path),
-        })
+    /// The current session, reconnecting with exponential backoff until it succeeds
+    fn session(&mut self) -> Arc<Sftp> {
+        if let Some(sftp) = self.sftp.lock().unwrap().as_ref() {
+            return Arc::clone(sftp);
+        }
+        let outage = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+        let sftp = loop {
+            match self.connect() {
+                Ok(sftp) => break sftp,
+                Err(e) => {
+                    let delay = jitter(backoff);
+                    warn!("Reconnecting failed, retrying in {:?}: {:#}", delay, e);
+                    thread::sleep(delay);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        };
+        info!(
+            "Reconnected, SFTP was unavailable for {} seconds",
+            outage.elapsed().as_secs()
+        );
+        let sftp = Arc::new(sftp);
+        *self.sftp.lock().unwrap() = Some(Arc::clone(&sftp));
+        sftp
+    }
+
+    /// Runs `operation`, and again on a new session if it failed because the
+    /// connection is gone. SFTP status errors are returned as they are.
+    fn with_session<T>(
+        &mut self,
+        mut operation: impl FnMut(&Sftp) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        loop {
+            match operation(&self.session()) {
+                Ok(value) => return Ok(value),
+                Err(e) if is_connection_lost(&e) => {
+                    warn!("SFTP session lost: {:#}", e);
+                    *self.sftp.lock().unwrap() = None;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 }
 
-impl Source for SshSource {
-    fn next(&mut self) -> Result<Data, Error> {
-        let path = loop {
-            let mut files = self.sftp.readdir(&self.directory)?;
-            if !files.is_empty() {
-                break files.pop().unwrap().0;
+/// Whether `e` comes from the SSH transport rather than from the SFTP server
+/// answering a request. File reads only keep the kind of the error, where the
+/// server's answers for missing files show up as `NotFound`.
+fn is_connection_lost(e: &Error) -> bool {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<ssh2::Error>() {
+            return matches!(e.code(), ErrorCode::Session(_));
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return e.kind() != io::ErrorKind::NotFound;
+        }
+    }
+    false
+}
+
+/// Sets the modification time of a claimed file to now
+fn touch(sftp: &Sftp, claimed: &Path) -> Result<(), Error> {
+    let now = now_secs();
+    sftp.setstat(
+        claimed,
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: Some(now),
+            mtime: Some(now),
+        },
+    )?;
+    Ok(())
+}
+
+fn is_no_such_file(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Claims on the server, laid out as in `claim`
+impl SshSource {
+    fn directory(&self) -> PathBuf {
+        PathBuf::from(&self.remote_parameters.path)
+    }
+
+    fn worker_directory(&self) -> PathBuf {
+        self.directory()
+            .join(PROCESSING_DIR)
+            .join(&self.options.claim.worker)
+    }
+
+    fn claimed_path(&self, name: &OsStr) -> PathBuf {
+        self.worker_directory().join(name)
+    }
+
+    /// Moves `from` into this worker's claims, false if it is gone or taken by another worker
+    fn take(&mut self, from: &Path) -> Result<bool, Error> {
+        let name = from
+            .file_name()
+            .ok_or_else(|| anyhow::format_err!("No file name in {}", from.display()))?;
+        let claimed = self.claimed_path(name);
+        let processing = self.directory().join(PROCESSING_DIR);
+        let worker_directory = self.worker_directory();
+        let mut attempts = 0;
+        let taken = self.with_session(|sftp| {
+            attempts += 1;
+            // Fails harmlessly when they exist
+            let _ = sftp.mkdir(&processing, 0o700);
+            let _ = sftp.mkdir(&worker_directory, 0o700);
+            match sftp.rename(
+                from,
+                &claimed,
+                Some(RenameFlags::ATOMIC | RenameFlags::NATIVE),
+            ) {
+                Ok(()) => (),
+                // An earlier attempt may have gone through before the connection died
+                Err(e) if is_no_such_file(&e) => {
+                    return Ok(attempts > 1 && sftp.stat(&claimed).is_ok())
+                }
+                Err(e) => return Err(Error::new(e).context(format!("Failed to claim {:?}", from))),
+            }
+            touch(sftp, &claimed)?;
+            Ok(true)
+        })?;
+        if taken {
+            self.heartbeat.hold(claimed);
+        }
+        Ok(taken)
+    }
+
+    /// Takes over leftover claims of this worker and stale claims of others
+    fn recover(&mut self) -> Result<(), Error> {
+        if self
+            .last_recovery
+            .is_some_and(|last| last.elapsed() < RECOVERY_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_recovery = Some(Instant::now());
+
+        let processing = self.directory().join(PROCESSING_DIR);
+        let claims = self.with_session(|sftp| {
+            let workers = match sftp.readdir(&processing) {
+                Ok(workers) => workers,
+                Err(e) if is_no_such_file(&e) => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut claims = Vec::new();
+            for (worker, stat) in workers {
+                if stat.is_dir() {
+                    claims.extend(sftp.readdir(&worker)?);
+                }
+            }
+            Ok(claims)
+        })?;
+
+        let own = self.worker_directory();
+        let cutoff = now_secs().saturating_sub(self.options.claim.timeout.as_secs());
+        for (path, stat) in claims {
+            if !stat.is_file() {
+                continue;
+            }
+            let is_own = path.parent() == Some(own.as_path());
+            if !is_own && stat.mtime.is_none_or(|mtime| mtime > cutoff) {
+                continue;
+            }
+            let taken = if is_own {
+                self.with_session(|sftp| touch(sftp, &path))?;
+                self.heartbeat.hold(path.clone());
+                true
             } else {
-                thread::sleep(Duration::from_secs(5));
+                self.take(&path)?
+            };
+            if taken {
+                warn!("Recovered claim on {}", path.display());
+                if let Some(name) = path.file_name() {
+                    self.recovered.push_back(name.to_os_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Claims and reads a recovered file or the oldest ready one, `None` if there is none
+    fn take_next(&mut self) -> Result<Option<Data>, Error> {
+        let directory = self.directory();
+        let min_age = self.options.min_age.unwrap_or(DEFAULT_MIN_AGE);
+        let path = loop {
+            if let Some(name) = self.recovered.pop_front() {
+                break directory.join(name);
+            }
+            self.recover()?;
+            if !self.recovered.is_empty() {
+                continue;
+            }
+
+            let files = self.with_session(|sftp| Ok(sftp.readdir(&directory)?))?;
+            match select_file(files, min_age) {
+                Some(path) if self.take(&path)? => break path,
+                Some(path) => info!("{} was claimed by another worker", path.display()),
+                None => return Ok(None),
             }
         };
 
-        let mut file = self.sftp.open(&path)?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)?;
+        // Read in full or not at all, a partial read is retried on a new session
+        let claimed = self.claimed_path(path.file_name().unwrap_or_default());
+        let contents = self.with_session(|sftp| {
+            let mut file = sftp.open(&claimed)?;
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+            Ok(contents)
+        })?;
 
-        Ok(Data {
+        Ok(Some(Data {
             id: path.into_os_string(),
             contents,
+            subdirectory: PathBuf::new(),
+        }))
+    }
+}
+
+/// A random delay between half of `backoff` and all of it, so that several
+/// clients of one host do not reconnect in lockstep
+fn jitter(backoff: Duration) -> Duration {
+    let mut random = [0u8; 4];
+    if rand_bytes(&mut random).is_err() {
+        return backoff;
+    }
+    let fraction = u32::from_le_bytes(random) as f64 / u32::MAX as f64;
+    backoff.mul_f64(0.5 + fraction / 2.0)
+}
+
+/// The oldest regular file that was last modified at least `min_age` ago. The
+/// age is by the server's clock against ours, so `min_age` should allow for skew.
+fn select_file(files: Vec<(PathBuf, FileStat)>, min_age: Duration) -> Option<PathBuf> {
+    let now = now_secs();
+    files
+        .into_iter()
+        .filter(|(path, stat)| stat.is_file() && !is_ignored(path))
+        .filter_map(|(path, stat)| Some((stat.mtime?, path)))
+        .filter(|(mtime, _)| now.saturating_sub(*mtime) >= min_age.as_secs())
+        .min()
+        .map(|(_, path)| path)
+}
+
+impl Source for SshSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        loop {
+            match self.take_next()? {
+                Some(data) => return Ok(data),
+                None => thread::sleep(Duration::from_secs(5)),
+            }
+        }
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        self.take_next()
+    }
+
+    fn confirm(&mut self, id: OsString) -> Result<(), Error> {
+        let name = Path::new(&id)
+            .file_name()
+            .ok_or_else(|| Error::msg("Unable to get input filename"))?;
+        let path = self.claimed_path(name);
+        self.heartbeat.release(&path);
+        let mut attempts = 0;
+        self.with_session(|sftp| {
+            attempts += 1;
+            match sftp.unlink(&path) {
+                Ok(()) => Ok(()),
+                // The connection died after an earlier attempt had gone through
+                Err(e) if attempts > 1 && is_no_such_file(&e) => {
+                    info!("{} was already removed", path.display());
+                    Ok(())
+                }
+                Err(e) => Err(e.into()),
+            }
         })
     }
+}
 
-    fn confirm(&self, id: OsString) -> Result<(), Error> {
-        self.sftp.unlink(&PathBuf::from(&id))?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LIBSSH2_FX_PERMISSION_DENIED and LIBSSH2_ERROR_SOCKET_RECV
+    const PERMISSION_DENIED: ErrorCode = ErrorCode::SFTP(3);
+    const SOCKET_RECV: ErrorCode = ErrorCode::Session(-43);
+
+    #[test]
+    fn only_transport_errors_reconnect() {
+        let error = |code| ssh2::Error::new(code, "failed");
+        assert!(!is_connection_lost(&Error::new(error(PERMISSION_DENIED))));
+        assert!(!is_connection_lost(
+            &Error::new(error(ErrorCode::SFTP(SFTP_NO_SUCH_FILE))).context("Failed to claim")
+        ));
+        assert!(is_connection_lost(&Error::new(error(SOCKET_RECV))));
+
+        // As returned by reads of files
+        let read_error = |code| Error::new(io::Error::from(error(code)));
+        assert!(is_connection_lost(&read_error(SOCKET_RECV)));
+        assert!(!is_connection_lost(&read_error(ErrorCode::SFTP(
+            SFTP_NO_SUCH_FILE
+        ))));
     }
 }
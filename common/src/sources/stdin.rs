@@ -0,0 +1,59 @@
+use crate::sources::{Data, Source};
+use anyhow::{Context, Error};
+use log::info;
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_NAME: &str = "stdin";
+
+/// The whole of standard input as a single file, `-` or `stdin:<file name>`.
+/// The name is what outputs are called, `stdin` if not given.
+pub struct StdinSource {
+    name: OsString,
+    read: bool,
+}
+
+impl StdinSource {
+    pub fn new(name: &str) -> Result<Self, Error> {
+        let name = match name {
+            "" => DEFAULT_NAME,
+            name => name,
+        };
+        if Path::new(name).file_name() != Some(name.as_ref()) {
+            return Err(anyhow::format_err!("Invalid file name for stdin: {}", name));
+        }
+        Ok(Self {
+            name: name.into(),
+            read: false,
+        })
+    }
+}
+
+impl Source for StdinSource {
+    fn next(&mut self) -> Result<Data, Error> {
+        self.next_available()?
+            .ok_or_else(|| Error::msg("Standard input has been read already"))
+    }
+
+    fn next_available(&mut self) -> Result<Option<Data>, Error> {
+        if self.read {
+            return Ok(None);
+        }
+        self.read = true;
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .context("Failed to read standard input")?;
+        info!("Read {} bytes from standard input", contents.len());
+        Ok(Some(Data {
+            contents,
+            id: self.name.clone(),
+            subdirectory: PathBuf::new(),
+        }))
+    }
+
+    fn confirm(&mut self, _id: OsString) -> Result<(), Error> {
+        Ok(())
+    }
+}
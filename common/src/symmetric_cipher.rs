@@ -1,20 +1,47 @@
 use openssl::rand::rand_bytes;
-use openssl::symm::{decrypt, encrypt, Cipher};
+use openssl::symm::{decrypt, decrypt_aead, encrypt, encrypt_aead, Cipher};
+use serde_derive::{Deserialize, Serialize};
 
-const KEY_LENGTH: usize = 32;
+pub const KEY_LENGTH: usize = 32;
 const IV_LENGTH: usize = 16;
+const GCM_NONCE_LENGTH: usize = 12;
+const GCM_TAG_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContentCipher {
+    /// Unauthenticated, used by bundle format versions 1 and 2
+    Aes256Cbc,
+    /// Tag appended to the ciphertext
+    Aes256Gcm,
+}
+
+impl ContentCipher {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentCipher::Aes256Cbc => "AES-256-CBC",
+            ContentCipher::Aes256Gcm => "AES-256-GCM",
+        }
+    }
+}
 
 pub struct SymmetricCipher {
     cipher: Cipher,
+    algorithm: ContentCipher,
     key: [u8; KEY_LENGTH],
     iv: [u8; IV_LENGTH],
 }
 
 impl SymmetricCipher {
     pub fn new(key: Option<&[u8]>) -> Self {
-        let cipher = Cipher::aes_256_cbc();
+        Self::with_algorithm(ContentCipher::Aes256Cbc, key)
+    }
+
+    pub fn with_algorithm(algorithm: ContentCipher, key: Option<&[u8]>) -> Self {
+        let cipher = match algorithm {
+            ContentCipher::Aes256Cbc => Cipher::aes_256_cbc(),
+            ContentCipher::Aes256Gcm => Cipher::aes_256_gcm(),
+        };
         assert_eq!(cipher.key_len(), KEY_LENGTH);
-        assert_eq!(cipher.iv_len().unwrap(), IV_LENGTH);
 
         // Generate key
         let key: [u8; KEY_LENGTH] = if let Some(key) = key {
@@ -23,34 +50,78 @@ impl SymmetricCipher {
         } else {
             let mut key = [0u8; KEY_LENGTH];
             rand_bytes(key.as_mut_slice()).unwrap();
+            assert_ne!(key, [0u8; KEY_LENGTH]);
             key
         };
-        assert_ne!(key, [0u8; KEY_LENGTH]);
 
-        // Null IV for single-use keys
+        // Null IV (or nonce) for single-use keys
         let iv = [0u8; IV_LENGTH];
 
-        SymmetricCipher { cipher, key, iv }
+        SymmetricCipher {
+            cipher,
+            algorithm,
+            key,
+            iv,
+        }
     }
 
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
-        let ciphertext = encrypt(
-            self.cipher,
-            self.key.as_slice(),
-            Some(self.iv.as_slice()),
-            plaintext,
-        )?;
-        Ok(ciphertext)
+        self.encrypt_aad(plaintext, &[])
     }
 
     pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
-        let plaintext = decrypt(
-            self.cipher,
-            self.key.as_slice(),
-            Some(self.iv.as_slice()),
-            ciphertext,
-        )?;
-        Ok(plaintext)
+        self.decrypt_aad(ciphertext, &[])
+    }
+
+    /// Encrypts `plaintext`, authenticating `aad` along with it. CBC ignores `aad`.
+    pub fn encrypt_aad(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self.algorithm {
+            ContentCipher::Aes256Cbc => Ok(encrypt(
+                self.cipher,
+                self.key.as_slice(),
+                Some(self.iv.as_slice()),
+                plaintext,
+            )?),
+            ContentCipher::Aes256Gcm => {
+                let mut tag = [0u8; GCM_TAG_LENGTH];
+                let mut ciphertext = encrypt_aead(
+                    self.cipher,
+                    self.key.as_slice(),
+                    Some(&self.iv[..GCM_NONCE_LENGTH]),
+                    aad,
+                    plaintext,
+                    &mut tag,
+                )?;
+                ciphertext.extend_from_slice(&tag);
+                Ok(ciphertext)
+            }
+        }
+    }
+
+    pub fn decrypt_aad(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self.algorithm {
+            ContentCipher::Aes256Cbc => Ok(decrypt(
+                self.cipher,
+                self.key.as_slice(),
+                Some(self.iv.as_slice()),
+                ciphertext,
+            )?),
+            ContentCipher::Aes256Gcm => {
+                let split = ciphertext
+                    .len()
+                    .checked_sub(GCM_TAG_LENGTH)
+                    .ok_or_else(|| anyhow::Error::msg("Ciphertext is shorter than the tag"))?;
+                let (ciphertext, tag) = ciphertext.split_at(split);
+                Ok(decrypt_aead(
+                    self.cipher,
+                    self.key.as_slice(),
+                    Some(&self.iv[..GCM_NONCE_LENGTH]),
+                    aad,
+                    ciphertext,
+                    tag,
+                )?)
+            }
+        }
     }
 
     pub fn get_key(&self) -> [u8; KEY_LENGTH] {
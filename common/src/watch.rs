@@ -1,13 +1,93 @@
-use notify::{Event, RecommendedWatcher, Watcher};
+use anyhow::Error;
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, Watcher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::mpsc::{channel, Receiver};
-use std::path::Path;
 
+/// Files that producers are still writing, or that are not meant for us
+const IGNORED_PREFIXES: &[&str] = &["."];
+const IGNORED_SUFFIXES: &[&str] = &[".tmp", ".part"];
+
+/// Which events mean that a file in a watched directory is complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Readiness {
+    /// Closed after writing, for producers that write the file in place
+    Close,
+    /// Renamed or linked into the directory, for producers that write it elsewhere
+    /// first. Files written in place would be picked up while still empty.
+    Rename,
+    /// Closed after writing or renamed into the directory
+    #[default]
+    CloseOrRename,
+}
+
+impl FromStr for Readiness {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "close" => Ok(Readiness::Close),
+            "rename" => Ok(Readiness::Rename),
+            "close-or-rename" => Ok(Readiness::CloseOrRename),
+            _ => Err(anyhow::format_err!("Unknown readiness strategy: {}", s)),
+        }
+    }
+}
+
+impl Readiness {
+    fn accepts(&self, kind: &EventKind) -> bool {
+        let closed = matches!(
+            kind,
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+        );
+        let renamed = matches!(kind, EventKind::Modify(ModifyKind::Name(RenameMode::To)));
+        // Hard links and linkat(2) of anonymous files only show up as a create
+        let created = matches!(kind, EventKind::Create(CreateKind::File));
+        match self {
+            Readiness::Close => closed,
+            Readiness::Rename => renamed || created,
+            Readiness::CloseOrRename => closed || renamed,
+        }
+    }
+
+    /// Paths of the event that are complete and not ignored
+    pub fn ready_paths(&self, event: Event) -> Vec<PathBuf> {
+        if !self.accepts(&event.kind) {
+            return Vec::new();
+        }
+        event
+            .paths
+            .into_iter()
+            .filter(|path| !is_ignored(path))
+            .collect()
+    }
+}
+
+/// Hidden files and temporary files of producers that rename into place
+pub fn is_ignored(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    IGNORED_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        || IGNORED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Events of the files in `path`, and in its subdirectories if `recursive`
 pub fn watch_files(
     path: &Path,
+    recursive: bool,
 ) -> Result<(RecommendedWatcher, Receiver<notify::Result<Event>>), anyhow::Error> {
     let (tx, rx) = channel();
 
     let mut watcher = notify::recommended_watcher(tx)?;
-    watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
+    let mode = if recursive {
+        notify::RecursiveMode::Recursive
+    } else {
+        notify::RecursiveMode::NonRecursive
+    };
+    watcher.watch(path, mode)?;
     Ok((watcher, rx))
-}
\ No newline at end of file
+}
@@ -0,0 +1,91 @@
+use crate::decrypt_bundle;
+use anyhow::{Context, Error};
+use common::{bundle::MAX_BUNDLE_SIZE, recipient::Identity};
+use log::info;
+use std::{
+    fs,
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+use zip::ZipArchive;
+
+/// Decrypts bundles given as paths, or a single bundle from stdin when no path
+/// or `-` is given. Plaintext goes to `output` if set and to stdout otherwise.
+pub fn run(
+    private_key: &Path,
+    bundles: &[PathBuf],
+    output: Option<&Path>,
+    extract: bool,
+) -> Result<(), Error> {
+    let private_key = Identity::from_file(private_key)?;
+
+    let from_stdin = bundles.is_empty() || bundles.iter().any(|b| b == Path::new("-"));
+    if from_stdin && bundles.len() > 1 {
+        return Err(Error::msg("stdin can not be combined with other bundles"));
+    }
+
+    let Some(output) = output else {
+        if bundles.len() > 1 {
+            return Err(Error::msg("Only one bundle can be written to stdout"));
+        }
+        let contents = read_bundle(bundles.first().filter(|_| !from_stdin))?;
+        let plaintext = decrypt_bundle(&contents, &private_key)?;
+        io::stdout().write_all(&plaintext)?;
+        return Ok(());
+    };
+
+    fs::create_dir_all(output).context(format!("Error creating output directory: {:?}", output))?;
+    if from_stdin {
+        let plaintext = decrypt_bundle(&read_bundle(None)?, &private_key)?;
+        write_plaintext(&plaintext, output, "stdin.zip", extract)?;
+    } else {
+        for bundle in bundles {
+            info!("Decrypting {}", bundle.display());
+            let plaintext = decrypt_bundle(&read_bundle(Some(bundle))?, &private_key)
+                .context(format!("Error decrypting {:?}", bundle))?;
+            let name = bundle
+                .file_name()
+                .ok_or_else(|| anyhow::format_err!("Bundle path has no file name: {:?}", bundle))?
+                .to_string_lossy();
+            write_plaintext(&plaintext, output, &name, extract)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_bundle(path: Option<&PathBuf>) -> Result<Vec<u8>, Error> {
+    let reader: Box<dyn Read> = match path {
+        Some(path) => {
+            Box::new(fs::File::open(path).context(format!("Error opening bundle: {:?}", path))?)
+        }
+        None => Box::new(io::stdin()),
+    };
+
+    // Anything longer is rejected by the parser, so there is no need to read it all
+    let mut contents = Vec::new();
+    reader
+        .take(MAX_BUNDLE_SIZE as u64 + 1)
+        .read_to_end(&mut contents)
+        .context("Error reading bundle")?;
+    Ok(contents)
+}
+
+fn write_plaintext(
+    plaintext: &[u8],
+    output: &Path,
+    name: &str,
+    extract: bool,
+) -> Result<(), Error> {
+    if extract {
+        let directory = output.join(Path::new(name).file_stem().unwrap_or(name.as_ref()));
+        info!(".. extracting to {}", directory.display());
+        ZipArchive::new(Cursor::new(plaintext))
+            .context("Plaintext is not a zip archive")?
+            .extract(&directory)?;
+    } else {
+        let path = output.join(name);
+        info!(".. writing {}", path.display());
+        fs::write(&path, plaintext).context(format!("Error writing {:?}", path))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use anyhow::Error;
+use common::maildir::Maildir;
+use lettre::{Message, SmtpTransport, Transport};
+
+/// Where decrypted submissions and alerts are sent
+pub enum Delivery {
+    Smtp(SmtpTransport),
+    /// Written straight into a local Maildir, without an SMTP server
+    Maildir(Maildir),
+}
+
+impl Delivery {
+    pub fn send(&self, message: &Message) -> Result<(), Error> {
+        match self {
+            Delivery::Smtp(mailer) => {
+                mailer.send(message)?;
+            }
+            Delivery::Maildir(maildir) => {
+                maildir.deliver(&message.formatted())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,81 +1,397 @@
-use anyhow::Error;
-use clap::Parser;
-use common::{bundle::Bundle, sources, sources::Data, symmetric_cipher::SymmetricCipher};
+use anyhow::{Context, Error};
+use clap::{Args, Parser, Subcommand};
+use common::{bundle::Bundle, maildir::Maildir, recipient::Identity, sources, sources::Data};
+use delivery::Delivery;
 use lettre::{
     message::{Attachment, Body, Message, MultiPart, SinglePart},
-    SmtpTransport, Transport,
-};
-use log::info;
-use openssl::{
-    pkey::Private,
-    rsa::{Padding, Rsa},
+    SmtpTransport,
 };
+use log::{info, warn};
+use replay::SeenStore;
+use sequence::SequenceTracker;
 use serde_json::Value;
-use std::{fs, io::Cursor, path::PathBuf};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Cursor, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use zip::ZipArchive;
 
+mod decrypt_file;
+mod delivery;
+mod replay;
+mod sequence;
+mod threshold;
+
+const SUBJECT: &str = "Hakulomake";
+
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(flatten)]
+    daemon: Option<DaemonArgs>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Args)]
+struct DaemonArgs {
+    /// Source URL, `file:///path`, `sftp://user@host/path`,
+    /// `mailbox+https://host/mailbox` to collect from a mailbox or
+    /// `maildir:///path` for mail delivered by a local MTA, a local path, or
+    /// `-` to decrypt standard input once
     #[arg(long)]
     source: String,
 
+    /// Decrypt the bundles available now and exit instead of waiting for more
+    #[arg(long, alias = "drain")]
+    once: bool,
+
     #[arg(long)]
     private_key: PathBuf,
 
-    #[arg(long)]
-    smtp_server: String,
+    #[arg(long, required_unless_present = "maildir")]
+    smtp_server: Option<String>,
 
+    /// Deliver into this local Maildir instead of through `--smtp-server`,
+    /// creating it if needed
+    #[arg(long, conflicts_with = "smtp_server")]
+    maildir: Option<PathBuf>,
+
+    /// Recipient of the decrypted submissions
     #[arg(long)]
     smtp_address: String,
+
+    /// SQLite database of delivered submission IDs, enables duplicate detection
+    #[arg(long)]
+    seen_db: Option<PathBuf>,
+
+    /// Reject bundles created more than this many seconds ago
+    #[arg(long, requires = "seen_db")]
+    max_age: Option<u64>,
+
+    /// Deliver duplicate, expired and unverified bundles with a tag in the
+    /// subject instead of dropping them
+    #[arg(long, requires = "seen_db")]
+    flag_replays: bool,
+
+    /// Reject bundles without a submission ID, such as those of older formats,
+    /// as their replays can not be detected
+    #[arg(long, requires = "seen_db")]
+    require_metadata: bool,
+
+    /// How many numbers behind the newest a bundle of the same sender may arrive
+    /// before it is reported as missing
+    #[arg(long, default_value_t = 10, requires = "seen_db")]
+    sequence_tolerance: u64,
+
+    /// Where to email reports of missing bundles and of bundles that can not
+    /// be decrypted, in addition to logging them
+    #[arg(long, requires = "seen_db")]
+    alert_address: Option<String>,
+
+    /// Directory to move bundles into that can never be decrypted, such as
+    /// malformed or tampered ones or those for another key. Without it they
+    /// are only logged and dropped.
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Decrypt the share of a threshold bundle that belongs to one private key
+    Share {
+        #[arg(long)]
+        private_key: PathBuf,
+
+        #[arg(long)]
+        bundle: PathBuf,
+
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Combine decrypted shares and write out the plaintext of a threshold bundle
+    Combine {
+        #[arg(long)]
+        bundle: PathBuf,
+
+        #[arg(long = "share", required = true)]
+        shares: Vec<PathBuf>,
+
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Decrypt bundles once and write the plaintext to a directory or stdout
+    DecryptFile {
+        #[arg(long)]
+        private_key: PathBuf,
+
+        /// Directory to write plaintext into, stdout if not given
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Extract the plaintext zip archives into per-bundle directories
+        #[arg(long, requires = "output")]
+        extract: bool,
+
+        /// Bundle files, stdin if none or `-`
+        bundles: Vec<PathBuf>,
+    },
 }
 
 fn main() -> Result<(), Error> {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
+    let cli = Cli::parse();
+
+    // Plaintext written to stdout must not be mixed with log lines
+    if !matches!(cli.command, Some(Command::DecryptFile { output: None, .. })) {
+        simple_logger::init_with_level(log::Level::Info).unwrap();
+    }
     info!("Started");
 
-    let cli = Cli::parse();
+    match (cli.command, cli.daemon) {
+        (
+            Some(Command::Share {
+                private_key,
+                bundle,
+                output,
+            }),
+            _,
+        ) => threshold::partial_decrypt(&private_key, &bundle, &output),
+        (
+            Some(Command::Combine {
+                bundle,
+                shares,
+                output,
+            }),
+            _,
+        ) => threshold::combine(&bundle, &shares, &output),
+        (
+            Some(Command::DecryptFile {
+                private_key,
+                output,
+                extract,
+                bundles,
+            }),
+            _,
+        ) => decrypt_file::run(&private_key, &bundles, output.as_deref(), extract),
+        (None, Some(daemon)) => run_daemon(daemon),
+        (None, None) => Err(Error::msg("No command given")),
+    }
+}
 
+fn run_daemon(cli: DaemonArgs) -> Result<(), Error> {
     info!("Loading private key");
-    let private_key_pem = fs::read(&cli.private_key)?;
-    let private_key = Rsa::private_key_from_pem(&private_key_pem)?;
+    let private_key = Identity::from_file(&cli.private_key)?;
+    info!("Private key ID: {}", private_key.key_id());
 
-    info!("Opening SMTP connection");
-    let mailer = SmtpTransport::builder_dangerous(&cli.smtp_server).build();
+    let mailer = match (&cli.smtp_server, &cli.maildir) {
+        (_, Some(path)) => {
+            info!("Delivering to Maildir {:?}", path);
+            Delivery::Maildir(Maildir::create(path)?)
+        }
+        (Some(smtp_server), None) => {
+            info!("Opening SMTP connection");
+            Delivery::Smtp(SmtpTransport::builder_dangerous(smtp_server).build())
+        }
+        (None, None) => return Err(Error::msg("No SMTP server or Maildir given")),
+    };
 
-    let mut source = sources::from_string(&cli.source)?;
-    loop {
-        let data = source.next()?;
-        handle_file(&data, &private_key, &mailer, &cli.smtp_address)?;
-        source.confirm(data.id)?;
+    let seen = match &cli.seen_db {
+        Some(path) => Some(SeenStore::open(
+            path,
+            cli.max_age.map(Duration::from_secs),
+            cli.require_metadata,
+        )?),
+        None => None,
+    };
+    let mut sequences = match &cli.seen_db {
+        Some(path) => Some(SequenceTracker::open(
+            path,
+            cli.sequence_tolerance,
+            cli.alert_address.is_some(),
+        )?),
+        None => None,
+    };
+    // Left over from a run that could not send them
+    if let (Some(sequences), Some(alert_address)) = (&sequences, &cli.alert_address) {
+        send_pending_alerts(sequences, &mailer, alert_address)?;
+    }
+
+    if let Some(quarantine) = &cli.quarantine {
+        fs::create_dir_all(quarantine)
+            .context(format!("Failed to create {}", quarantine.display()))?;
     }
+
+    let mut source = sources::from_string(&cli.source)?;
+    let once = cli.once || sources::is_stdin(&cli.source);
+    let handled = sources::process(source.as_mut(), once, |data| {
+        handle_file(
+            data,
+            &private_key,
+            &mailer,
+            &cli,
+            seen.as_ref(),
+            sequences.as_mut(),
+        )
+    })?;
+    info!("Processed {} bundles, exiting", handled);
+    Ok(())
 }
 
 fn handle_file(
     data: &Data,
-    private_key: &Rsa<Private>,
-    mailer: &SmtpTransport,
-    smtp_address: &str,
+    private_key: &Identity,
+    mailer: &Delivery,
+    cli: &DaemonArgs,
+    seen: Option<&SeenStore>,
+    sequences: Option<&mut SequenceTracker>,
 ) -> Result<(), Error> {
-    let bundle: Bundle = bincode::deserialize(&data.contents).unwrap();
-
-    let mut buf = vec![0; private_key.size() as usize];
-    private_key
-        .private_decrypt(
-            bundle.enc_key.as_slice(),
-            buf.as_mut_slice(),
-            Padding::PKCS1,
-        )
-        .unwrap();
+    // Retrying a bundle that is at fault only stops the rest from being decrypted
+    let (bundle, plaintext) = match open_bundle(&data.contents, private_key) {
+        Ok(opened) => opened,
+        Err(err) if err.is_permanent() => return reject(data, &err, mailer, cli),
+        Err(err) => return Err(err.into()),
+    };
+
+    // The metadata can only be trusted once the bundle has been authenticated
+    let mut subject = SUBJECT.to_string();
+    if let Some(seen) = seen {
+        match (seen.check(bundle.metadata.as_ref())?, &bundle.metadata) {
+            (None, None) => warn!("Bundle has no submission ID, duplicates can not be detected"),
+            (None, Some(_)) => (),
+            (Some(rejection), metadata) => {
+                match metadata {
+                    Some(metadata) => {
+                        warn!("Submission {}: {}", metadata.submission_id_hex(), rejection)
+                    }
+                    None => warn!("Bundle {}: {}", data.id.to_string_lossy(), rejection),
+                }
+                if !cli.flag_replays {
+                    warn!(".. dropping");
+                    return Ok(());
+                }
+                subject = format!("[{}] {}", rejection.label(), SUBJECT);
+            }
+        }
+    }
+
+    send_email(&plaintext, mailer, &cli.smtp_address, &subject)?;
+    if let (Some(seen), Some(metadata)) = (seen, &bundle.metadata) {
+        seen.record(metadata)?;
+    }
+
+    let sequence = bundle.metadata.as_ref().and_then(|m| m.sequence.as_ref());
+    if let (Some(sequences), Some(sequence)) = (sequences, sequence) {
+        for anomaly in sequences.observe(sequence)? {
+            warn!("{}", sequence::report(&sequence.sender, &anomaly));
+        }
+        if let Some(alert_address) = &cli.alert_address {
+            send_pending_alerts(sequences, mailer, alert_address)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the stored anomaly reports, each forgotten only once it is sent. A
+/// failure is retried with the next bundle.
+fn send_pending_alerts(
+    sequences: &SequenceTracker,
+    mailer: &Delivery,
+    alert_address: &str,
+) -> Result<(), Error> {
+    for alert in sequences.pending_alerts()? {
+        if let Err(err) = send_alert(&alert.report, mailer, alert_address) {
+            warn!("Failed to send alert, retrying later: {:#}", err);
+            break;
+        }
+        sequences.alert_sent(alert.id)?;
+    }
+    Ok(())
+}
+
+fn open_bundle(
+    contents: &[u8],
+    private_key: &Identity,
+) -> Result<(Bundle, Vec<u8>), common::Error> {
+    let bundle = Bundle::from_bytes(contents)?;
+    let plaintext = common::open(&bundle, private_key)?.into_vec();
+    Ok((bundle, plaintext))
+}
 
-    let sym_enc_key = &buf[0..32];
+/// Moves a bundle that can never be decrypted out of the way and reports it
+fn reject(
+    data: &Data,
+    err: &common::Error,
+    mailer: &Delivery,
+    cli: &DaemonArgs,
+) -> Result<(), Error> {
+    let mut report = format!("Bundle {}: {}", data.id.to_string_lossy(), err);
+    match &cli.quarantine {
+        Some(quarantine) => {
+            let path = quarantine_file(quarantine, data)?;
+            report = format!("{}, moved to {}", report, path.display());
+        }
+        None => report = format!("{}, dropped", report),
+    }
+    warn!("{}", report);
+    if let Some(alert_address) = &cli.alert_address {
+        send_alert(&report, mailer, alert_address)?;
+    }
+    Ok(())
+}
 
-    let cipher = SymmetricCipher::new(Some(sym_enc_key));
-    let plaintext = cipher.decrypt(&bundle.ciphertext)?;
-    send_email(&plaintext, mailer, smtp_address)?;
+/// Writes the bundle into `quarantine` under its file name, numbered if the
+/// name is taken
+fn quarantine_file(quarantine: &Path, data: &Data) -> Result<PathBuf, Error> {
+    let file_name = data.file_name()?.to_string_lossy().into_owned();
+    let mut path = quarantine.join(&file_name);
+    let mut taken = 0;
+    let mut file = loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => break file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                taken += 1;
+                path = quarantine.join(format!("{}.{}", file_name, taken));
+            }
+            Err(e) => {
+                return Err(Error::new(e).context(format!("Failed to create {}", path.display())))
+            }
+        }
+    };
+    file.write_all(&data.contents)
+        .and_then(|_| file.sync_all())
+        .context(format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+fn send_alert(report: &str, mailer: &Delivery, alert_address: &str) -> Result<(), Error> {
+    let message = Message::builder()
+        .from(
+            "Hakulomake <noreply@localhost.localdomain>"
+                .parse()
+                .unwrap(),
+        )
+        .to(alert_address.parse()?)
+        .subject(format!("[ALERT] {}", SUBJECT))
+        .singlepart(SinglePart::plain(report.to_string()))?;
+
+    info!("Sending alert");
+    mailer.send(&message)?;
     Ok(())
 }
 
-fn send_email(zip: &[u8], mailer: &SmtpTransport, smtp_address: &str) -> Result<(), Error> {
+fn decrypt_bundle(contents: &[u8], private_key: &Identity) -> Result<Vec<u8>, Error> {
+    Ok(open_bundle(contents, private_key)?.1)
+}
+
+fn send_email(
+    zip: &[u8],
+    mailer: &Delivery,
+    smtp_address: &str,
+    subject: &str,
+) -> Result<(), Error> {
     let mut zip_archive = ZipArchive::new(Cursor::new(zip))?;
     let text = if let Ok(f) = zip_archive.by_name("formdata.json") {
         let data: Value = serde_json::from_reader(f)?;
@@ -92,7 +408,7 @@ fn send_email(zip: &[u8], mailer: &SmtpTransport, smtp_address: &str) -> Result<
                 .unwrap(),
         )
         .to(smtp_address.parse()?)
-        .subject("Hakulomake")
+        .subject(subject)
         .multipart(
             MultiPart::mixed()
                 .singlepart(SinglePart::plain(text))
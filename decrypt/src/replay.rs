@@ -0,0 +1,211 @@
+use anyhow::{Context, Error};
+use common::bundle::Metadata;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Why a bundle should not be delivered
+#[derive(Debug)]
+pub enum Rejection {
+    /// The submission was already delivered, at `first_seen` seconds since the epoch
+    Duplicate {
+        first_seen: u64,
+    },
+    TooOld {
+        age: Duration,
+    },
+    /// Without a submission ID and creation time a replay can not be told apart
+    NoMetadata,
+}
+
+impl Rejection {
+    /// Short tag for the subject of flagged emails
+    pub fn label(&self) -> &'static str {
+        match self {
+            Rejection::Duplicate { .. } => "DUPLICATE",
+            Rejection::TooOld { .. } => "EXPIRED",
+            Rejection::NoMetadata => "UNVERIFIED",
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::Duplicate { first_seen } => {
+                write!(f, "already delivered at {} (Unix time)", first_seen)
+            }
+            Rejection::TooOld { age } => write!(f, "created {} seconds ago", age.as_secs()),
+            Rejection::NoMetadata => write!(f, "no submission ID, replays can not be detected"),
+        }
+    }
+}
+
+/// Persistent record of delivered submission IDs
+pub struct SeenStore {
+    connection: Connection,
+    max_age: Option<Duration>,
+    require_metadata: bool,
+}
+
+impl SeenStore {
+    /// With `max_age` submissions are forgotten once they are that old, as
+    /// they are rejected as too old from then on
+    pub fn open(
+        path: &Path,
+        max_age: Option<Duration>,
+        require_metadata: bool,
+    ) -> Result<Self, Error> {
+        let connection = Connection::open(path)
+            .context(format!("Error opening seen-ID database: {:?}", path))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS seen (
+                submission_id TEXT PRIMARY KEY,
+                created INTEGER NOT NULL,
+                first_seen INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS seen_created ON seen (created);",
+        )?;
+        Ok(Self {
+            connection,
+            max_age,
+            require_metadata,
+        })
+    }
+
+    /// Bundles without metadata pass unless it is required
+    pub fn check(&self, metadata: Option<&Metadata>) -> Result<Option<Rejection>, Error> {
+        let Some(metadata) = metadata else {
+            return Ok(self.require_metadata.then_some(Rejection::NoMetadata));
+        };
+        let first_seen: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT first_seen FROM seen WHERE submission_id = ?1",
+                params![metadata.submission_id_hex()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(first_seen) = first_seen {
+            return Ok(Some(Rejection::Duplicate {
+                first_seen: first_seen as u64,
+            }));
+        }
+
+        let age = metadata.age();
+        match self.max_age {
+            Some(max_age) if age > max_age => Ok(Some(Rejection::TooOld { age })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Marks the submission as delivered. Called only after delivery, so that a
+    /// failed attempt can be retried.
+    pub fn record(&self, metadata: &Metadata) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.connection.execute(
+            "INSERT OR IGNORE INTO seen (submission_id, created, first_seen) VALUES (?1, ?2, ?3)",
+            params![
+                metadata.submission_id_hex(),
+                metadata.created as i64,
+                now as i64
+            ],
+        )?;
+        if let Some(max_age) = self.max_age {
+            let cutoff = now.saturating_sub(max_age.as_secs());
+            self.connection.execute(
+                "DELETE FROM seen WHERE created < ?1",
+                params![cutoff as i64],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const MAX_AGE: Duration = Duration::from_secs(3600);
+
+    fn open_store(name: &str, require_metadata: bool) -> (SeenStore, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("seen-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = SeenStore::open(&path, Some(MAX_AGE), require_metadata).unwrap();
+        (store, path)
+    }
+
+    /// Metadata of a submission created `age` ago
+    fn submission(age: Duration) -> Metadata {
+        let mut metadata = Metadata::generate(None).unwrap();
+        metadata.created -= age.as_secs();
+        metadata
+    }
+
+    fn stored(store: &SeenStore) -> i64 {
+        store
+            .connection
+            .query_row("SELECT COUNT(*) FROM seen", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn recorded_submissions_are_duplicates() {
+        let (store, path) = open_store("duplicates", false);
+        let metadata = submission(Duration::ZERO);
+        assert!(store.check(Some(&metadata)).unwrap().is_none());
+        // Not before delivery
+        assert!(store.check(Some(&metadata)).unwrap().is_none());
+        store.record(&metadata).unwrap();
+        assert!(matches!(
+            store.check(Some(&metadata)).unwrap(),
+            Some(Rejection::Duplicate { .. })
+        ));
+        assert!(store
+            .check(Some(&submission(Duration::ZERO)))
+            .unwrap()
+            .is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_submissions_are_rejected_and_forgotten() {
+        let (store, path) = open_store("max-age", false);
+        let old = submission(MAX_AGE * 2);
+        assert!(matches!(
+            store.check(Some(&old)).unwrap(),
+            Some(Rejection::TooOld { .. })
+        ));
+        let recent = submission(MAX_AGE / 2);
+        store.record(&old).unwrap();
+        store.record(&recent).unwrap();
+        // Still rejected as too old without the entry
+        assert_eq!(stored(&store), 1);
+        assert!(matches!(
+            store.check(Some(&old)).unwrap(),
+            Some(Rejection::TooOld { .. })
+        ));
+        assert!(matches!(
+            store.check(Some(&recent)).unwrap(),
+            Some(Rejection::Duplicate { .. })
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_metadata_is_rejected_when_required() {
+        let (store, path) = open_store("optional", false);
+        assert!(store.check(None).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+
+        let (store, path) = open_store("required", true);
+        assert!(matches!(
+            store.check(None).unwrap(),
+            Some(Rejection::NoMetadata)
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{Context, Error};
+use common::bundle::Sequence;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// Gaps wider than this are reported at once instead of tracked number by number
+const MAX_TRACKED_GAP: u64 = 1000;
+
+/// Irregularity in the sequence numbers of one sender
+#[derive(Debug)]
+pub enum Anomaly {
+    /// Numbers `from..=to` were skipped and have not arrived within the tolerance
+    Missing { from: u64, to: u64 },
+    /// A number arrived after `highest`, beyond the tolerance or more than once
+    Late { number: u64, highest: u64 },
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::Missing { from, to } if from == to => write!(f, "number {} is missing", from),
+            Anomaly::Missing { from, to } => write!(f, "numbers {} to {} are missing", from, to),
+            Anomaly::Late { number, highest } => write!(
+                f,
+                "number {} arrived late or repeated, already at {}",
+                number, highest
+            ),
+        }
+    }
+}
+
+/// An anomaly report that has not been confirmed as sent
+pub struct PendingAlert {
+    pub id: i64,
+    pub report: String,
+}
+
+/// The report of an anomaly in the numbers of `sender`
+pub fn report(sender: &str, anomaly: &Anomaly) -> String {
+    format!("Bundles from sender {}: {}", sender, anomaly)
+}
+
+/// Persistent record of the highest number and the outstanding gaps of each sender
+pub struct SequenceTracker {
+    connection: Connection,
+    tolerance: u64,
+    alerts: bool,
+}
+
+impl SequenceTracker {
+    /// `tolerance` is how far behind the highest number a bundle may arrive
+    /// before it is considered lost. With `alerts` the anomalies are kept
+    /// until `alert_sent`, as the gaps they report are forgotten.
+    pub fn open(path: &Path, tolerance: u64, alerts: bool) -> Result<Self, Error> {
+        let connection = Connection::open(path)
+            .context(format!("Error opening sequence database: {:?}", path))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS senders (
+                sender TEXT PRIMARY KEY,
+                highest INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS missing (
+                sender TEXT NOT NULL,
+                number INTEGER NOT NULL,
+                PRIMARY KEY (sender, number)
+            );
+            CREATE TABLE IF NOT EXISTS alerts (
+                id INTEGER PRIMARY KEY,
+                report TEXT NOT NULL
+            );",
+        )?;
+        Ok(Self {
+            connection,
+            tolerance,
+            alerts,
+        })
+    }
+
+    /// Reports of earlier anomalies that are still to be sent, oldest first
+    pub fn pending_alerts(&self) -> Result<Vec<PendingAlert>, Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, report FROM alerts ORDER BY id")?;
+        let rows = statement.query_map([], |row| {
+            Ok(PendingAlert {
+                id: row.get(0)?,
+                report: row.get(1)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn alert_sent(&self, id: i64) -> Result<(), Error> {
+        self.connection
+            .execute("DELETE FROM alerts WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Records a delivered number and returns what is wrong with the sequence so far
+    pub fn observe(&mut self, sequence: &Sequence) -> Result<Vec<Anomaly>, Error> {
+        let tx = self.connection.transaction()?;
+        let number = sequence.number as i64;
+        let highest: Option<i64> = tx
+            .query_row(
+                "SELECT highest FROM senders WHERE sender = ?1",
+                params![sequence.sender],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let mut anomalies = Vec::new();
+        match highest {
+            // Nothing to compare the first number of a sender to
+            None => {
+                tx.execute(
+                    "INSERT INTO senders (sender, highest) VALUES (?1, ?2)",
+                    params![sequence.sender, number],
+                )?;
+            }
+            Some(highest) if number > highest => {
+                let gap = (number - highest - 1) as u64;
+                if gap > MAX_TRACKED_GAP {
+                    anomalies.push(Anomaly::Missing {
+                        from: highest as u64 + 1,
+                        to: sequence.number - 1,
+                    });
+                } else {
+                    for missing in highest + 1..number {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO missing (sender, number) VALUES (?1, ?2)",
+                            params![sequence.sender, missing],
+                        )?;
+                    }
+                }
+                tx.execute(
+                    "UPDATE senders SET highest = ?2 WHERE sender = ?1",
+                    params![sequence.sender, number],
+                )?;
+            }
+            Some(highest) => {
+                let filled = tx.execute(
+                    "DELETE FROM missing WHERE sender = ?1 AND number = ?2",
+                    params![sequence.sender, number],
+                )?;
+                if filled == 0 {
+                    anomalies.push(Anomaly::Late {
+                        number: sequence.number,
+                        highest: highest as u64,
+                    });
+                }
+            }
+        }
+
+        // Gaps that have fallen out of the tolerance window are reported once
+        let current: i64 = tx.query_row(
+            "SELECT highest FROM senders WHERE sender = ?1",
+            params![sequence.sender],
+            |row| row.get(0),
+        )?;
+        let cutoff = current.saturating_sub(self.tolerance as i64);
+        let lost = {
+            let mut statement = tx.prepare(
+                "SELECT number FROM missing WHERE sender = ?1 AND number < ?2 ORDER BY number",
+            )?;
+            let rows = statement
+                .query_map(params![sequence.sender, cutoff], |row| row.get::<_, i64>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        tx.execute(
+            "DELETE FROM missing WHERE sender = ?1 AND number < ?2",
+            params![sequence.sender, cutoff],
+        )?;
+
+        for number in lost {
+            let number = number as u64;
+            match anomalies.last_mut() {
+                Some(Anomaly::Missing { to, .. }) if *to + 1 == number => *to = number,
+                _ => anomalies.push(Anomaly::Missing {
+                    from: number,
+                    to: number,
+                }),
+            }
+        }
+
+        // Stored with the deletion, so that a gap is not forgotten unreported
+        if self.alerts {
+            for anomaly in &anomalies {
+                tx.execute(
+                    "INSERT INTO alerts (report) VALUES (?1)",
+                    params![report(&sequence.sender, anomaly)],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(anomalies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(number: u64) -> Sequence {
+        Sequence {
+            sender: "site/sales".to_string(),
+            number,
+        }
+    }
+
+    #[test]
+    fn lost_numbers_are_alerted_until_sent() {
+        let path = std::env::temp_dir().join(format!("sequence-alerts-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut tracker = SequenceTracker::open(&path, 2, true).unwrap();
+        for number in [1, 3, 4] {
+            assert!(tracker.observe(&sequence(number)).unwrap().is_empty());
+        }
+        // 2 is now out of the window and no longer tracked
+        let anomalies = tracker.observe(&sequence(5)).unwrap();
+        assert!(matches!(
+            anomalies[..],
+            [Anomaly::Missing { from: 2, to: 2 }]
+        ));
+        drop(tracker);
+
+        // Not sent before a restart
+        let tracker = SequenceTracker::open(&path, 2, true).unwrap();
+        let pending = tracker.pending_alerts().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            pending[0].report,
+            "Bundles from sender site/sales: number 2 is missing"
+        );
+        tracker.alert_sent(pending[0].id).unwrap();
+        assert!(tracker.pending_alerts().unwrap().is_empty());
+
+        // Nothing is kept without alerts
+        let mut tracker = SequenceTracker::open(&path, 2, false).unwrap();
+        assert_eq!(tracker.observe(&sequence(9)).unwrap().len(), 1);
+        assert!(tracker.pending_alerts().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::{Context, Error};
+use common::{
+    bundle::{Bundle, KeyWrap, ThresholdWrap},
+    recipient::Identity,
+    shamir::Share,
+};
+use log::info;
+use openssl::sha::sha256;
+use serde_derive::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// One officer's decrypted share, tied to the bundle it came from
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialDecryption {
+    bundle: String,
+    share: Share,
+}
+
+fn bundle_digest(bundle: &Bundle) -> String {
+    hex::encode(sha256(&bundle.ciphertext))
+}
+
+fn read_threshold_bundle(path: &Path) -> Result<(Bundle, ThresholdWrap), Error> {
+    let bundle = Bundle::read_from_path(path)?;
+    match &bundle.key_wrap {
+        KeyWrap::Threshold(threshold_wrap) => {
+            let threshold_wrap = threshold_wrap.clone();
+            Ok((bundle, threshold_wrap))
+        }
+        _ => Err(Error::msg("Bundle is not a threshold bundle")),
+    }
+}
+
+pub fn partial_decrypt(private_key: &Path, bundle: &Path, output: &Path) -> Result<(), Error> {
+    let private_key = Identity::from_file(private_key)?;
+    let key_id = private_key.key_id();
+    let (bundle, threshold_wrap) = read_threshold_bundle(bundle)?;
+
+    let wrapped_share = threshold_wrap
+        .shares
+        .iter()
+        .find(|share| share.key_id == key_id)
+        .ok_or_else(|| anyhow::format_err!("Bundle has no share for key {}", key_id))?;
+    info!(
+        "Decrypting share {} of {}, {} needed",
+        wrapped_share.index,
+        threshold_wrap.shares.len(),
+        threshold_wrap.threshold
+    );
+
+    let value = private_key.unwrap_share(&wrapped_share.key_wrap, &wrapped_share.enc_share)?;
+    let partial = PartialDecryption {
+        bundle: bundle_digest(&bundle),
+        share: Share {
+            index: wrapped_share.index,
+            value,
+        },
+    };
+    fs::write(output, serde_json::to_vec(&partial)?)
+        .context(format!("Error writing share: {:?}", output))?;
+    Ok(())
+}
+
+pub fn combine(
+    bundle: &Path,
+    share_paths: &[impl AsRef<Path>],
+    output: &Path,
+) -> Result<(), Error> {
+    let (bundle, threshold_wrap) = read_threshold_bundle(bundle)?;
+    let digest = bundle_digest(&bundle);
+
+    let mut shares = Vec::with_capacity(share_paths.len());
+    for path in share_paths {
+        let path = path.as_ref();
+        let data = fs::read(path).context(format!("Error reading share: {:?}", path))?;
+        let partial: PartialDecryption =
+            serde_json::from_slice(&data).context(format!("Invalid share file: {:?}", path))?;
+        if partial.bundle != digest {
+            return Err(anyhow::format_err!(
+                "Share {:?} belongs to a different bundle",
+                path
+            ));
+        }
+        shares.push(partial.share);
+    }
+
+    info!(
+        "Combining {} shares, {} needed",
+        shares.len(),
+        threshold_wrap.threshold
+    );
+    let plaintext = common::open_with_shares(&bundle, &shares)?;
+    fs::write(output, plaintext).context(format!("Error writing output: {:?}", output))?;
+    Ok(())
+}
@@ -1,19 +1,74 @@
+use crate::sequence::SequenceConfig;
+use common::rsa_keys::KeyPolicy;
 use serde_derive::Deserialize;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Either a single key, or `key_urls.len()` keys of which any `threshold` can decrypt
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TargetKeys {
+    Single {
+        key_url: String,
+    },
+    Threshold {
+        threshold: u8,
+        key_urls: Vec<String>,
+    },
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Target {
     pub name: String,
-    pub key_url: String,
+    #[serde(flatten)]
+    pub keys: TargetKeys,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigFile {
     pub targets: Vec<Target>,
+    #[serde(default)]
+    pub key_policy: KeyPolicy,
+    pub sequence: Option<SequenceConfig>,
+    /// Target names for each input subdirectory, such as `contact = ["sales"]`
+    #[serde(default)]
+    pub routes: HashMap<String, Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub targets: Vec<Target>,
-    pub output: PathBuf,
+    pub key_policy: KeyPolicy,
+    pub sequence: Option<SequenceConfig>,
+    pub routes: HashMap<String, Vec<String>>,
+}
+
+impl Config {
+    /// Checks that every route refers to configured targets
+    pub fn validate_routes(&self) -> Result<(), anyhow::Error> {
+        for (subdirectory, names) in &self.routes {
+            for name in names {
+                if !self.targets.iter().any(|target| target.name == *name) {
+                    return Err(anyhow::format_err!(
+                        "Route for {:?} refers to unknown target {}",
+                        subdirectory,
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Targets for input from `subdirectory`. Without a route that is all of them.
+    pub fn targets_for(&self, subdirectory: &Path) -> Vec<&Target> {
+        match self.routes.get(subdirectory.to_string_lossy().as_ref()) {
+            None => self.targets.iter().collect(),
+            Some(names) => self
+                .targets
+                .iter()
+                .filter(|target| names.contains(&target.name))
+                .collect(),
+        }
+    }
 }
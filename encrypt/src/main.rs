@@ -1,19 +1,20 @@
 use anyhow::Context;
 use clap::Parser;
 use log::info;
-use openssl::rsa::Padding;
 use std::fs;
 use std::path::PathBuf;
 
-use common::bundle::Bundle;
-use common::rsa_keys::{KeyFromUrl, RsaPubkey};
+use common::bundle::{Bundle, Metadata};
+use common::recipient::{PublicJwk, Recipient};
+use common::rsa_keys::{KeyFromUrl, KeyPolicy};
+use common::sinks::{self, Sink};
 use common::sources;
 use common::sources::Data;
-use common::symmetric_cipher::SymmetricCipher;
 
-use crate::config::{Config, ConfigFile, Target};
+use crate::config::{Config, ConfigFile, Target, TargetKeys};
 
 mod config;
+mod sequence;
 
 #[derive(Debug, Parser)]
 struct Cli {
@@ -22,11 +23,19 @@ struct Cli {
 
     //#[arg(long)]
     //cache: PathBuf,
+    /// Source URL, `file:///path`, `sftp://user@host/path` or
+    /// `http://127.0.0.1:8080/upload` to listen for uploads, a local path, or
+    /// `-` to encrypt standard input once
     #[arg(long)]
-    input: PathBuf,
+    input: String,
 
+    /// Output directory or URL, `file:///path` or `s3://bucket/prefix`
     #[arg(long)]
-    output: PathBuf,
+    output: String,
+
+    /// Encrypt the files available now and exit instead of waiting for more
+    #[arg(long, alias = "drain")]
+    once: bool,
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -40,24 +49,40 @@ fn main() -> Result<(), anyhow::Error> {
     let config_file: ConfigFile = toml::from_str(&config_string)?;
     let config = Config {
         targets: config_file.targets,
-        output: cli.output,
+        key_policy: config_file.key_policy,
+        sequence: config_file.sequence,
+        routes: config_file.routes,
     };
+    config.validate_routes()?;
 
-    let mut source = sources::from_string(cli.input.to_str().unwrap())?;
-    loop {
-        let data = source.next()?;
-        handle_data(&data, &config)?;
-        source.confirm(data.id)?;
-    }
+    let mut sink = sinks::from_string(&cli.output)?;
+    let mut source = sources::from_string(&cli.input)?;
+    let once = cli.once || sources::is_stdin(&cli.input);
+    let handled = sources::process(source.as_mut(), once, |data| {
+        handle_data(data, &config, sink.as_mut())
+    })?;
+    info!("Encrypted {} files, exiting", handled);
+    Ok(())
 }
 
-fn handle_data(data: &Data, config: &Config) -> Result<(), anyhow::Error> {
+fn handle_data(data: &Data, config: &Config, sink: &mut dyn Sink) -> Result<(), anyhow::Error> {
     info!("Handling {:?}", &data.id);
-    for target in &config.targets {
+    // Every target gets the same submission under the same ID. Routes give
+    // targets only some submissions, so each counts its own numbers.
+    let submission = Metadata::generate(None)?;
+    let relative = data.subdirectory.join(data.file_name()?);
+    for target in config.targets_for(&data.subdirectory) {
         info!(".. with target {}", &target.name);
-        let bundle = encrypt_for(&data.contents, target).context("Error encrypting")?;
-        bundle
-            .write_to_path(&config.output, &target.name, &data.id)
+        let metadata = Metadata {
+            sequence: match &config.sequence {
+                Some(sequence) => Some(sequence.next(&target.name)?),
+                None => None,
+            },
+            ..submission.clone()
+        };
+        let bundle = encrypt_for(&data.contents, target, &config.key_policy, &metadata)
+            .context("Error encrypting")?;
+        sink.write(&bundle, &target.name, &relative)
             .context("Error writing output file")?;
     }
 
@@ -65,24 +90,35 @@ fn handle_data(data: &Data, config: &Config) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-fn encrypt_for(plaintext: &[u8], target: &Target) -> Result<Bundle, anyhow::Error> {
-    let sym_cipher = SymmetricCipher::new(None);
-    let ciphertext = sym_cipher.encrypt(plaintext)?;
-
-    let rsa_key = RsaPubkey::from_url(&target.key_url)
+fn fetch_recipient(key_url: &str, key_policy: &KeyPolicy) -> Result<Recipient, anyhow::Error> {
+    PublicJwk::from_url(key_url)
         .context("Getting public key from URL")?
-        .into_rsa_key()
-        .context("Converting keyfile to a key")?;
-    dbg!(&rsa_key.size());
-    let mut wrapped_key = vec![0; rsa_key.size() as usize];
-    rsa_key.public_encrypt(
-        sym_cipher.get_key().as_slice(),
-        wrapped_key.as_mut_slice(),
-        Padding::PKCS1,
-    )?;
+        .into_recipient(key_policy)
+        .context(format!("Checking the key from {}", key_url))
+}
 
-    Ok(Bundle {
-        ciphertext,
-        enc_key: wrapped_key,
-    })
+fn encrypt_for(
+    plaintext: &[u8],
+    target: &Target,
+    key_policy: &KeyPolicy,
+    metadata: &Metadata,
+) -> Result<Bundle, anyhow::Error> {
+    let bundle = match &target.keys {
+        TargetKeys::Single { key_url } => common::seal(
+            plaintext,
+            &fetch_recipient(key_url, key_policy)?,
+            metadata.clone(),
+        )?,
+        TargetKeys::Threshold {
+            threshold,
+            key_urls,
+        } => {
+            let recipients = key_urls
+                .iter()
+                .map(|key_url| fetch_recipient(key_url, key_policy))
+                .collect::<Result<Vec<_>, _>>()?;
+            common::seal_threshold(plaintext, *threshold, &recipients, metadata.clone())?
+        }
+    };
+    Ok(bundle)
 }
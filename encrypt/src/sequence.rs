@@ -0,0 +1,108 @@
+use anyhow::Context;
+use common::bundle::Sequence;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind::NotFound, Write};
+use std::path::PathBuf;
+
+/// Numbers submissions so that the decrypting side can notice lost bundles.
+/// Each target has its own numbers, as routes send it only some submissions,
+/// and bundles name `<sender>/<target>` as their sender.
+#[derive(Debug, Deserialize)]
+pub struct SequenceConfig {
+    /// Unique name of this encrypting instance
+    pub sender: String,
+    /// Holds the last number handed out per target, survives restarts
+    pub state_file: PathBuf,
+}
+
+impl SequenceConfig {
+    /// Last numbers per target, from lines of `<target> <number>`. A single
+    /// number is left from before numbers were counted per target. Its stream
+    /// was under the plain sender name, so every target starts anew.
+    fn read_state(&self) -> Result<BTreeMap<String, u64>, anyhow::Error> {
+        let contents = match fs::read_to_string(&self.state_file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == NotFound => return Ok(BTreeMap::new()),
+            Err(e) => {
+                return Err(e).context(format!(
+                    "Error reading sequence state file: {:?}",
+                    self.state_file
+                ))
+            }
+        };
+        let invalid = || format!("Invalid sequence state file: {:?}", self.state_file);
+        if contents.trim().parse::<u64>().is_ok() {
+            return Ok(BTreeMap::new());
+        }
+        let mut last = BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (target, number) = line.rsplit_once(' ').with_context(invalid)?;
+            last.insert(target.to_string(), number.parse().with_context(invalid)?);
+        }
+        Ok(last)
+    }
+
+    /// Hands out the next number for `target`. It is stored before use, so a
+    /// crash can skip a number but never repeat one.
+    pub fn next(&self, target: &str) -> Result<Sequence, anyhow::Error> {
+        let mut last = self.read_state()?;
+        let number = last.get(target).copied().unwrap_or_default() + 1;
+        last.insert(target.to_string(), number);
+
+        let mut tmp_path = self.state_file.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        for (target, number) in &last {
+            writeln!(file, "{} {}", target, number)?;
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp_path, &self.state_file).context(format!(
+            "Error writing sequence state file: {:?}",
+            self.state_file
+        ))?;
+
+        Ok(Sequence {
+            sender: format!("{}/{}", self.sender, target),
+            number,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_target_counts_its_own_numbers() {
+        let state_file = std::env::temp_dir().join(format!("sequence-test-{}", std::process::id()));
+        fs::write(&state_file, "5\n").unwrap();
+        let config = SequenceConfig {
+            sender: "site".to_string(),
+            state_file: state_file.clone(),
+        };
+
+        let numbers: Vec<(String, u64)> = ["sales", "support", "sales"]
+            .iter()
+            .map(|target| {
+                let sequence = config.next(target).unwrap();
+                (sequence.sender, sequence.number)
+            })
+            .collect();
+        assert_eq!(
+            numbers,
+            [
+                ("site/sales".to_string(), 1),
+                ("site/support".to_string(), 1),
+                ("site/sales".to_string(), 2),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&state_file).unwrap(),
+            "sales 2\nsupport 1\n"
+        );
+        fs::remove_file(&state_file).unwrap();
+    }
+}
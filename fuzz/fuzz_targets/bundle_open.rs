@@ -0,0 +1,31 @@
+#![no_main]
+
+use common::bundle::Bundle;
+use common::hybrid_kem::HybridPrivateKey;
+use common::recipient::Identity;
+use libfuzzer_sys::fuzz_target;
+use openssl::rsa::Rsa;
+use std::sync::OnceLock;
+
+static IDENTITIES: OnceLock<Vec<Identity>> = OnceLock::new();
+
+fn identities() -> &'static [Identity] {
+    IDENTITIES.get_or_init(|| {
+        vec![
+            Identity::Rsa(Rsa::generate(2048).expect("Generating RSA key")),
+            Identity::Hybrid(
+                HybridPrivateKey::generate()
+                    .and_then(|key| key.into_identity())
+                    .expect("Generating hybrid key"),
+            ),
+        ]
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(bundle) = Bundle::from_bytes(data) {
+        for identity in identities() {
+            let _ = common::open(&bundle, identity);
+        }
+    }
+});
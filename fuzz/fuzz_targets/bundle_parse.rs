@@ -0,0 +1,11 @@
+#![no_main]
+
+use common::bundle::Bundle;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(bundle) = Bundle::from_bytes(data) {
+        let encoded = bundle.to_bytes().expect("Parsed bundle failed to serialize");
+        Bundle::from_bytes(&encoded).expect("Serialized bundle failed to parse");
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use common::hybrid_kem::HybridPrivateKey;
+use common::recipient::PublicJwk;
+use common::rsa_keys::{KeyFromString, KeyPolicy, RsaPrivateKey};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(data) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    if let Ok(jwk) = PublicJwk::from_raw_string(data) {
+        let _ = jwk.into_recipient(&KeyPolicy::default());
+    }
+    if let Ok(key) = HybridPrivateKey::from_raw_string(data) {
+        let _ = key.into_identity();
+    }
+    if let Ok(key) = RsaPrivateKey::from_raw_string(data) {
+        let _ = key.into_rsa_key();
+    }
+});
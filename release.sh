@@ -12,7 +12,7 @@ if ! podman image inspect "${BUILDER_IMAGE}" >/dev/null 2>&1; then
     build_image
 else
     built="$(date -d "$(podman image inspect "${BUILDER_IMAGE}" | jq -r '.[0].Created')" +%s)"
-    modified="$(date -r Dockerfile.build +%s)"
+    modified="$(date -r Dockerfile.build_el7 +%s)"
     if [[ $modified -gt $built ]]; then
         echo "Build image out of date, rebuilding"
         build_image
@@ -29,5 +29,5 @@ fi
 
 podman run --rm -ti ${PODMAN_BUILD_OPTS:-} -v "$PWD:/app:Z" -v "$PWD/el7-target:/app/target:Z" "${BUILDER_IMAGE}" bash -c "
     cd /app
-    cargo build --release
+    cargo build --release --features common/vendored-openssl
 "
@@ -1,13 +1,15 @@
 use anyhow::Context;
 use clap::Parser;
-use common::bundle::Bundle;
-use log::info;
-use notify::event::AccessKind;
-use notify::{Event, EventKind, RecommendedWatcher, Watcher};
+use common::bundle::{Bundle, MAX_BUNDLE_SIZE};
+use common::sources;
+use common::watch::{watch_files, Readiness};
+use log::{info, warn};
+use notify::event::{CreateKind, EventKind};
 use reqwest::blocking::multipart::{Form, Part};
 use std::fs::{remove_file, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
 
 mod test_server;
 
@@ -18,31 +20,90 @@ struct Cli {
 
     #[arg(long)]
     target: String,
+
+    /// When a bundle is complete: close, rename or close-or-rename
+    #[arg(long, default_value = "close-or-rename")]
+    readiness: Readiness,
+
+    /// Send the bundles in the directory now and exit instead of watching it
+    #[arg(long, alias = "drain")]
+    once: bool,
+
+    /// With `--once`, leave bundles modified less than this many seconds ago
+    /// for the next run, as they may still be written
+    #[arg(long, default_value_t = 10)]
+    min_age: u64,
 }
 
-fn file_to_form(path: &PathBuf) -> Result<Form, anyhow::Error> {
-    let file = File::open(path).context(format!("Error opening input file: {:?}", &path))?;
-    let bundle: Bundle = bincode::deserialize_from(&file).context("Error deserializing")?;
-    let form = Form::new()
-        .part(
-            "files[]",
-            Part::bytes(bundle.ciphertext)
-                .file_name("form.zip")
-                .mime_str("application/zip")
-                .context("Failed to set MIME type")?,
-        )
-        .text("key", hex::encode(bundle.enc_key));
+/// The bundle file as it is, so that everything needed to open it arrives,
+/// and its path relative to the input directory
+fn file_to_form(path: &Path, relative: &Path) -> Result<Form, anyhow::Error> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .context(format!("Error opening bundle: {:?}", path))?
+        .take(MAX_BUNDLE_SIZE as u64 + 1)
+        .read_to_end(&mut contents)
+        .context(format!("Error reading bundle: {:?}", path))?;
+    Bundle::from_bytes(&contents).context("Not a valid bundle")?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::format_err!("Bundle path has no file name: {:?}", path))?
+        .to_string_lossy()
+        .into_owned();
+    let relative = relative
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let form = Form::new().text("path", relative).part(
+        "bundle",
+        Part::bytes(contents)
+            .file_name(file_name)
+            .mime_str("application/octet-stream")
+            .context("Failed to set MIME type")?,
+    );
     Ok(form)
 }
 
-fn watch_files(
-    path: &Path,
-) -> Result<(RecommendedWatcher, Receiver<notify::Result<Event>>), anyhow::Error> {
-    let (tx, rx) = channel();
+/// Sends a bundle and removes it, returns whether the target accepted it
+fn send_file(path: &Path, input: &Path, target: &str) -> Result<bool, anyhow::Error> {
+    info!("Sending {}", path.display());
+    let relative = path.strip_prefix(input).unwrap_or(path);
+    let form = file_to_form(path, relative).context("Failed to construct form")?;
+    let response = reqwest::blocking::Client::new()
+        .post(target)
+        .multipart(form)
+        .send()
+        .context("HTTP request failed")?;
 
-    let mut watcher = notify::recommended_watcher(tx)?;
-    watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
-    Ok((watcher, rx))
+    if !response.status().is_success() {
+        log::error!("HTTP request failed: {:?}", response);
+        if let Ok(text) = response.text() {
+            log::error!("HTTP reponse text: {}", text);
+        }
+        return Ok(false);
+    }
+    log::info!("{} sent succesfully", path.display());
+    remove_file(path).context("File deletion failed")?;
+    Ok(true)
+}
+
+/// Sends the bundles of a new subdirectory, which may have been written before
+/// it was watched. A bundle still being written fails here and is sent on its
+/// own event instead.
+fn send_directory(directory: &Path, input: &Path, target: &str) {
+    let paths = match sources::settled_files(directory, true, Duration::ZERO) {
+        Ok(paths) => paths,
+        Err(err) => {
+            warn!("Failed to list {}: {:#}", directory.display(), err);
+            return;
+        }
+    };
+    for path in paths {
+        if let Err(err) = send_file(&path, input, target) {
+            warn!("Failed to send {}: {:#}", path.display(), err);
+        }
+    }
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -51,29 +112,39 @@ fn main() -> Result<(), anyhow::Error> {
 
     let cli = Cli::parse();
 
-    let (_watcher, events) = watch_files(&cli.input)?;
+    if cli.once {
+        let mut failed = 0;
+        let min_age = Duration::from_secs(cli.min_age);
+        for path in sources::settled_files(&cli.input, true, min_age)? {
+            if !send_file(&path, &cli.input, &cli.target)? {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow::format_err!("{} bundles were not accepted", failed));
+        }
+        info!("Sent all bundles, exiting");
+        return Ok(());
+    }
+
+    // Bundles are routed into subdirectories per target
+    let (_watcher, events) = watch_files(&cli.input, true)?;
     for event in events {
         let event = event?;
-        if let EventKind::Access(AccessKind::Close(_)) = &event.kind {
-            for path in event.paths {
-                info!("Sending {}", path.display());
-                let form = file_to_form(&path).context("Failed to construct form")?;
-                let response = reqwest::blocking::Client::new()
-                    .post(&cli.target)
-                    .multipart(form)
-                    .send()
-                    .context("HTTP request failed")?;
-
-                if !response.status().is_success() {
-                    log::error!("HTTP request failed: {:?}", response);
-                    if let Ok(text) = response.text() {
-                        log::error!("HTTP reponse text: {}", text);
-                    }
-                } else {
-                    log::info!("{} sent succesfully", path.display());
-                    remove_file(path).context("File deletion failed")?;
-                }
+        if let EventKind::Create(CreateKind::Folder) = event.kind {
+            for directory in &event.paths {
+                send_directory(directory, &cli.input, &cli.target);
+            }
+            continue;
+        }
+        for path in cli.readiness.ready_paths(event) {
+            if path.is_dir() {
+                // Moved in as a whole
+                send_directory(&path, &cli.input, &cli.target);
+            } else if path.is_file() {
+                send_file(&path, &cli.input, &cli.target)?;
             }
+            // Otherwise already sent under an earlier event for the same file
         }
     }
 
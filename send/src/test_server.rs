@@ -1,34 +1,146 @@
+use common::bundle::Bundle;
+use futures::TryStreamExt;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
-use warp::{Rejection, Reply, Filter};
+use std::sync::{Arc, Mutex};
 use warp::http::StatusCode;
+use warp::hyper::body::Buf;
 use warp::multipart::{FormData, Part};
-use futures::TryStreamExt;
+use warp::{Filter, Rejection, Reply};
+
+/// Uploaded bundles by ID, for testing sources that collect them
+#[derive(Default)]
+struct Mailbox {
+    next_id: u64,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+type SharedMailbox = Arc<Mutex<Mailbox>>;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
 
-async fn upload(form: FormData) -> Result<impl Reply, Rejection> {
+#[derive(Debug)]
+struct InvalidBundle;
+
+impl warp::reject::Reject for InvalidBundle {}
+
+async fn upload(form: FormData, mailbox: SharedMailbox) -> Result<impl Reply, Rejection> {
     let parts: Vec<Part> = form.try_collect().await.map_err(|e| {
         eprintln!("form error: {}", e);
         warp::reject::reject()
     })?;
-    dbg!(parts);
+    for part in parts {
+        if part.filename().is_none() {
+            continue;
+        }
+        let contents = part
+            .stream()
+            .try_fold(Vec::new(), |mut contents, buf| async move {
+                contents.extend_from_slice(buf.chunk());
+                Ok(contents)
+            })
+            .await
+            .map_err(|e| {
+                eprintln!("form error: {}", e);
+                warp::reject::reject()
+            })?;
+        // Stored as sent, it has to open at the other end
+        if let Err(e) = Bundle::from_bytes(&contents) {
+            eprintln!("invalid bundle: {}", e);
+            return Err(warp::reject::custom(InvalidBundle));
+        }
+        let mut mailbox = mailbox.lock().unwrap();
+        let id = format!("{:08}", mailbox.next_id);
+        mailbox.next_id += 1;
+        println!("Stored upload {} ({} bytes)", id, contents.len());
+        mailbox.files.insert(id, contents);
+    }
     Ok("success")
 }
 
+/// Requires `Authorization: Bearer $MAILBOX_TOKEN` for the mailbox if it is set
+fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|header: Option<String>| async move {
+            match std::env::var("MAILBOX_TOKEN") {
+                Ok(token) if header != Some(format!("Bearer {}", token)) => {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+fn mailbox_routes(
+    mailbox: SharedMailbox,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    let with_mailbox = warp::any().map(move || mailbox.clone());
+    let list = warp::path!("mailbox")
+        .and(warp::get())
+        .and(with_mailbox.clone())
+        .map(|mailbox: SharedMailbox| {
+            let mailbox = mailbox.lock().unwrap();
+            warp::reply::json(&mailbox.files.keys().collect::<Vec<_>>()).into_response()
+        });
+    let download = warp::path!("mailbox" / String)
+        .and(warp::get())
+        .and(with_mailbox.clone())
+        .map(
+            |id: String, mailbox: SharedMailbox| match mailbox.lock().unwrap().files.get(&id) {
+                Some(contents) => contents.clone().into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+        );
+    let delete = warp::path!("mailbox" / String)
+        .and(warp::delete())
+        .or(warp::path!("mailbox" / String / "ack").and(warp::post()))
+        .unify()
+        .and(with_mailbox)
+        .map(|id: String, mailbox: SharedMailbox| {
+            match mailbox.lock().unwrap().files.remove(&id) {
+                Some(_) => {
+                    println!("Removed {}", id);
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        });
+    authorized().and(list.or(download).unify().or(delete).unify())
+}
+
 #[allow(dead_code)]
 #[tokio::main]
 async fn main() {
+    let mailbox = SharedMailbox::default();
+    let upload_mailbox = mailbox.clone();
     let upload_route = warp::path("upload")
         .and(warp::post())
         .and(warp::multipart::form().max_length(50_000_000))
+        .and(warp::any().map(move || upload_mailbox.clone()))
         .and_then(upload);
 
-    let router = upload_route.recover(handle_rejection);
-    println!("Server started at localhost:8080");
-    warp::serve(router).run(([0, 0, 0, 0], 8080)).await;
+    let router = upload_route
+        .or(mailbox_routes(mailbox))
+        .recover(handle_rejection);
+    let port = std::env::args()
+        .nth(1)
+        .map(|port| port.parse().expect("Invalid port"))
+        .unwrap_or(8080);
+    println!("Server started at localhost:{}", port);
+    warp::serve(router).run(([0, 0, 0, 0], port)).await;
 }
 
 async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
     let (code, message) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found".to_string())
+    } else if err.find::<InvalidBundle>().is_some() {
+        (StatusCode::BAD_REQUEST, "Not a bundle".to_string())
+    } else if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Unauthorized".to_string())
     } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
         (StatusCode::BAD_REQUEST, "Payload too large".to_string())
     } else {
@@ -40,4 +152,4 @@ async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Inf
     };
 
     Ok(warp::reply::with_status(message, code))
-}
\ No newline at end of file
+}
@@ -0,0 +1,43 @@
+//! Bundles sent by queue-sender, collected with `MailboxSource` and opened
+
+mod support;
+
+use common::bundle::{Bundle, Metadata};
+use common::recipient::Identity;
+use std::fs;
+use support::{hybrid_key, scratch_directory, send_once, TestServer, TOKEN};
+
+#[test]
+fn sent_bundles_are_collected_opened_and_removed_on_confirm() {
+    let server = TestServer::start();
+    let directory = scratch_directory("mailbox");
+    let (recipient, private_key) = hybrid_key(&directory);
+    let input = directory.join("input");
+    fs::create_dir(&input).unwrap();
+    let plaintext = b"PK form data".to_vec();
+    let bundle = common::seal(&plaintext, &recipient, Metadata::generate(None).unwrap()).unwrap();
+    fs::write(input.join("form.zip"), bundle.to_bytes().unwrap()).unwrap();
+    assert!(send_once(&input, &server));
+
+    let token_file = directory.join("token");
+    fs::write(&token_file, TOKEN).unwrap();
+    let mut source = common::sources::from_string(&format!(
+        "mailbox+{}?token_file={}&poll=1",
+        server.url("/mailbox"),
+        token_file.display()
+    ))
+    .unwrap();
+
+    let data = source.next_available().unwrap().unwrap();
+    assert_eq!(data.id, "00000000");
+    let identity = Identity::from_file(&private_key).unwrap();
+    let received = Bundle::from_bytes(&data.contents).unwrap();
+    assert_eq!(*common::open(&received, &identity).unwrap(), plaintext[..]);
+    // Left in the mailbox until it is confirmed
+    assert_eq!(server.listing(), ["00000000"]);
+
+    source.confirm(data.id).unwrap();
+    assert!(server.listing().is_empty());
+    assert!(source.next_available().unwrap().is_none());
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,55 @@
+//! What queue-sender posts has to open like the bundle it read
+
+mod support;
+
+use common::bundle::{Bundle, Metadata};
+use common::recipient::Identity;
+use std::fs;
+use std::path::Path;
+use support::{hybrid_key, scratch_directory, send_once, TestServer};
+
+#[test]
+fn sent_bundles_open_at_the_other_end() {
+    let server = TestServer::start();
+    let directory = scratch_directory("sender");
+    let (recipient, private_key) = hybrid_key(&directory);
+    let input = directory.join("input");
+    fs::create_dir(&input).unwrap();
+
+    let plaintext = b"PK form data".to_vec();
+    let metadata = Metadata::generate(None).unwrap();
+    let bundle = common::seal(&plaintext, &recipient, metadata).unwrap();
+    fs::write(input.join("form.zip"), bundle.to_bytes().unwrap()).unwrap();
+
+    assert!(send_once(&input, &server));
+    assert!(!input.join("form.zip").exists());
+    let ids = server.listing();
+    assert_eq!(ids.len(), 1);
+
+    let received = Bundle::from_bytes(&server.get(&format!("/mailbox/{}", ids[0]))).unwrap();
+    assert_eq!(received.metadata, bundle.metadata);
+    let identity = Identity::from_file(&private_key).unwrap();
+    assert_eq!(*common::open(&received, &identity).unwrap(), plaintext[..]);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn bundles_routed_into_subdirectories_are_sent() {
+    let server = TestServer::start();
+    let directory = scratch_directory("routed");
+    let (recipient, _) = hybrid_key(&directory);
+    let input = directory.join("input");
+    fs::create_dir(&input).unwrap();
+
+    let bundle = common::seal(b"PK", &recipient, Metadata::generate(None).unwrap()).unwrap();
+    bundle
+        .write_to_path(&input, "target", Path::new("forms/form.zip"))
+        .unwrap();
+    let path = input.join("target/forms/form.zip");
+    assert!(path.exists());
+
+    assert!(send_once(&input, &server));
+    assert!(!path.exists());
+    assert_eq!(server.listing().len(), 1);
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,113 @@
+//! The test-server, keys and queue-sender runs shared by the integration tests
+
+#![allow(dead_code)]
+
+use common::hybrid_kem::HybridPrivateKey;
+use common::recipient::{PublicJwk, Recipient};
+use common::rsa_keys::{KeyFromString, KeyPolicy};
+use reqwest::blocking::Client;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const TOKEN: &str = "mailbox-test-token";
+
+/// Killed when the test ends, passed or not
+pub struct TestServer {
+    child: Child,
+    port: u16,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new(env!("CARGO_BIN_EXE_test-server"))
+            .arg(port.to_string())
+            .env("MAILBOX_TOKEN", TOKEN)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Self { child, port };
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "Server did not start"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    /// A mailbox path, with the token
+    pub fn get(&self, path: &str) -> Vec<u8> {
+        Client::new()
+            .get(self.url(path))
+            .bearer_auth(TOKEN)
+            .send()
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .bytes()
+            .unwrap()
+            .to_vec()
+    }
+
+    pub fn listing(&self) -> Vec<String> {
+        serde_json::from_slice(&self.get("/mailbox")).unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// An empty directory of its own for the test
+pub fn scratch_directory(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("queue-sender-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// A new hybrid key, the private half written to `directory` for `Identity::from_file`
+pub fn hybrid_key(directory: &Path) -> (Recipient, PathBuf) {
+    let private_key = HybridPrivateKey::generate().unwrap();
+    let public = serde_json::to_string(&private_key.public()).unwrap();
+    let recipient = PublicJwk::from_raw_string(&public)
+        .unwrap()
+        .into_recipient(&KeyPolicy::default())
+        .unwrap();
+    let path = directory.join("private.json");
+    fs::write(&path, serde_json::to_string(&private_key).unwrap()).unwrap();
+    (recipient, path)
+}
+
+/// Runs `queue-sender --once` on `input`, returns whether it succeeded
+pub fn send_once(input: &Path, server: &TestServer) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_queue-sender"))
+        .arg("--input")
+        .arg(input)
+        .arg("--target")
+        .arg(server.url("/upload"))
+        .args(["--once", "--min-age", "0"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
@@ -16,7 +16,7 @@ fn main() {
 
     println!(
         "{}",
-        serde_json::to_string(&RsaPubkey::from_parts("RSA".to_string(), n, e,))
+        serde_json::to_string(&RsaPubkey::from_parts("RSA".to_string(), n, e).with_alg("RSA1_5"))
             .expect("Failed to encode key as JSON")
     )
 }
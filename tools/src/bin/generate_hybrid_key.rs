@@ -0,0 +1,27 @@
+use common::hybrid_kem::HybridPrivateKey;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let filename = args.get(1).expect("Private key file name not provided");
+
+    let key = HybridPrivateKey::generate().expect("Unable to generate key");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(filename)
+        .expect("Unable to create private key file");
+    serde_json::to_writer(&mut file, &key).expect("Failed to encode private key as JSON");
+    file.write_all(b"\n")
+        .expect("Unable to write private key file");
+
+    println!(
+        "{}",
+        serde_json::to_string(&key.public()).expect("Failed to encode public key as JSON")
+    )
+}
@@ -0,0 +1,122 @@
+use clap::Parser;
+use common::bundle::{Bundle, KeyWrap};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const EXIT_MALFORMED: u8 = 1;
+const EXIT_UNREADABLE: u8 = 2;
+
+/// Prints the unencrypted header of bundle files. No private key is needed.
+///
+/// Exits with 0 if every file is a well-formed bundle, 1 if any is malformed
+/// and 2 if any could not be read.
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Print one JSON object per file instead of text
+    #[arg(long)]
+    json: bool,
+
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+}
+
+fn describe(bundle: &Bundle, version: Option<u8>) -> Value {
+    let mut info = json!({
+        "format_version": version,
+        "key_wrap": bundle.key_wrap.algorithm(),
+        "content_cipher": bundle.content_algorithm(),
+        "key_id": bundle.key_id,
+        "wrapped_key_length": bundle.enc_key.len(),
+        "ciphertext_length": bundle.ciphertext.len(),
+        "metadata": bundle.has_metadata(),
+    });
+    if let Some(metadata) = &bundle.metadata {
+        info["submission_id"] = json!(metadata.submission_id_hex());
+        info["created"] = json!(metadata.created);
+        if let Some(sequence) = &metadata.sequence {
+            info["sender"] = json!(sequence.sender);
+            info["sequence"] = json!(sequence.number);
+        }
+    }
+    if let KeyWrap::Threshold(threshold_wrap) = &bundle.key_wrap {
+        info["threshold"] = json!(threshold_wrap.threshold);
+        info["shares"] = threshold_wrap
+            .shares
+            .iter()
+            .map(|share| {
+                json!({
+                    "index": share.index,
+                    "key_id": share.key_id,
+                    "key_wrap": share.key_wrap.algorithm(),
+                    "wrapped_share_length": share.enc_share.len(),
+                })
+            })
+            .collect();
+    }
+    info
+}
+
+const TEXT_FIELDS: &[&str] = &[
+    "format_version",
+    "key_wrap",
+    "content_cipher",
+    "key_id",
+    "wrapped_key_length",
+    "ciphertext_length",
+    "metadata",
+    "submission_id",
+    "created",
+    "sender",
+    "sequence",
+    "threshold",
+];
+
+fn print_text(file: &str, info: &Value) {
+    println!("{}", file);
+    for field in TEXT_FIELDS {
+        match info.get(field) {
+            Some(Value::String(s)) => println!("  {}: {}", field, s),
+            Some(value) => println!("  {}: {}", field, value),
+            None => (),
+        }
+    }
+    for share in info["shares"].as_array().into_iter().flatten() {
+        println!("  share: {}", share);
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let mut exit_code = 0;
+    for path in &cli.files {
+        let file = path.display().to_string();
+        let info = match fs::read(path) {
+            Err(e) => {
+                exit_code = exit_code.max(EXIT_UNREADABLE);
+                json!({ "file": file, "error": e.to_string() })
+            }
+            Ok(data) => match Bundle::from_bytes(&data) {
+                Err(e) => {
+                    exit_code = exit_code.max(EXIT_MALFORMED);
+                    json!({ "file": file, "format_version": Bundle::format_version(&data), "error": e.to_string() })
+                }
+                Ok(bundle) => describe(&bundle, Bundle::format_version(&data)),
+            },
+        };
+
+        if cli.json {
+            let mut info = info;
+            info["file"] = json!(file);
+            println!("{}", info);
+        } else if let Some(error) = info.get("error") {
+            eprintln!("{}: {}", file, error.as_str().unwrap_or_default());
+        } else {
+            print_text(&file, &info);
+        }
+    }
+
+    ExitCode::from(exit_code)
+}
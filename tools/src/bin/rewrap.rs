@@ -0,0 +1,196 @@
+use anyhow::Context;
+use clap::Parser;
+use common::bundle::{Bundle, KeyWrap};
+use common::recipient::{Identity, PublicJwk, Recipient};
+use common::rsa_keys::{KeyFromString, KeyFromUrl, KeyPolicy};
+use serde_derive::Deserialize;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Re-wraps queued bundles from a retired key to a new one, leaving the ciphertext untouched
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Private key the bundles are currently wrapped to
+    #[arg(long)]
+    old_private_key: PathBuf,
+
+    /// Public JWK of the new key, as a file or an http(s) URL
+    #[arg(long)]
+    new_public_key: String,
+
+    /// queue-encrypt configuration whose `key_policy` the new key has to
+    /// satisfy, the default policy if not given
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Directories to rewrite in place
+    #[arg(required = true)]
+    directories: Vec<PathBuf>,
+}
+
+/// The part of the queue-encrypt configuration that applies here
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    key_policy: KeyPolicy,
+}
+
+fn key_policy(config: Option<&Path>) -> Result<KeyPolicy, anyhow::Error> {
+    let Some(config) = config else {
+        return Ok(KeyPolicy::default());
+    };
+    let config_string =
+        fs::read_to_string(config).context(format!("Error reading config: {:?}", config))?;
+    let config_file: ConfigFile =
+        toml::from_str(&config_string).context(format!("Invalid config: {:?}", config))?;
+    Ok(config_file.key_policy)
+}
+
+enum Outcome {
+    Rewrapped,
+    Skipped(String),
+}
+
+fn load_recipient(key: &str, policy: &KeyPolicy) -> Result<Recipient, anyhow::Error> {
+    let jwk = if key.starts_with("http://") || key.starts_with("https://") {
+        PublicJwk::from_url(key)?
+    } else {
+        PublicJwk::from_raw_string(
+            &fs::read_to_string(key).context(format!("Error reading public key: {}", key))?,
+        )?
+    };
+    jwk.into_recipient(policy)
+}
+
+fn rewrap(bundle: &mut Bundle, old: &Identity, new: &Recipient) -> Result<Outcome, anyhow::Error> {
+    let old_id = old.key_id();
+
+    if let KeyWrap::Threshold(threshold_wrap) = &mut bundle.key_wrap {
+        let mut found = false;
+        for share in threshold_wrap
+            .shares
+            .iter_mut()
+            .filter(|share| share.key_id == old_id)
+        {
+            let value = old.unwrap_share(&share.key_wrap, &share.enc_share)?;
+            (share.key_wrap, share.enc_share) = new.wrap_share(&value)?;
+            share.key_id = new.key_id();
+            found = true;
+        }
+        return Ok(if found {
+            Outcome::Rewrapped
+        } else {
+            Outcome::Skipped("no share for the old key".to_string())
+        });
+    }
+
+    match &bundle.key_id {
+        Some(key_id) if *key_id != old_id => {
+            return Ok(Outcome::Skipped(format!("wrapped to key {}", key_id)))
+        }
+        _ => (),
+    }
+
+    let key = match old.unwrap_key(&bundle.key_wrap, &bundle.enc_key) {
+        Ok(key) => key,
+        // Unversioned bundles carry no key ID, so failing to unwrap is the only signal
+        Err(e) if bundle.key_id.is_none() => return Ok(Outcome::Skipped(e.to_string())),
+        Err(e) => return Err(e),
+    };
+    (bundle.key_wrap, bundle.enc_key) = new.wrap_key(&key)?;
+    bundle.key_id = Some(new.key_id());
+    Ok(Outcome::Rewrapped)
+}
+
+/// Replaces `path` by writing a hidden temporary file next to it and renaming it over
+fn replace_atomically(path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::Error::msg("Path has no file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".rewrap.tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let permissions = fs::metadata(path)?.permissions();
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.set_permissions(permissions)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        e.into()
+    })
+}
+
+fn process_file(path: &Path, old: &Identity, new: &Recipient) -> Result<Outcome, anyhow::Error> {
+    let mut bundle = match Bundle::read_from_path(path) {
+        Ok(bundle) => bundle,
+        Err(e) => return Ok(Outcome::Skipped(format!("not a bundle: {}", e))),
+    };
+
+    let outcome = rewrap(&mut bundle, old, new)?;
+    if let Outcome::Rewrapped = outcome {
+        replace_atomically(path, &bundle.to_bytes()?)?;
+    }
+    Ok(outcome)
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+
+    let old = Identity::from_file(&cli.old_private_key)?;
+    let policy = key_policy(cli.config.as_deref())?;
+    let new = load_recipient(&cli.new_public_key, &policy)?;
+    eprintln!(
+        "Rewrapping from key {} to key {}",
+        old.key_id(),
+        new.key_id()
+    );
+
+    let mut rewrapped = 0;
+    let mut skipped = 0;
+    let mut failed = 0;
+    for directory in &cli.directories {
+        let entries =
+            fs::read_dir(directory).context(format!("Error reading directory: {:?}", directory))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type()?.is_file() || entry.file_name().to_string_lossy().starts_with('.')
+            {
+                continue;
+            }
+
+            match process_file(&path, &old, &new) {
+                Ok(Outcome::Rewrapped) => {
+                    eprintln!("{}: rewrapped", path.display());
+                    rewrapped += 1;
+                }
+                Ok(Outcome::Skipped(reason)) => {
+                    eprintln!("{}: skipped, {}", path.display(), reason);
+                    skipped += 1;
+                }
+                Err(e) => {
+                    eprintln!("{}: failed, {:#}", path.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!(
+        "Processed {} files: {} rewrapped, {} skipped, {} failed",
+        rewrapped + skipped + failed,
+        rewrapped,
+        skipped,
+        failed
+    );
+    if failed > 0 {
+        return Err(anyhow::format_err!("{} files failed", failed));
+    }
+    Ok(())
+}